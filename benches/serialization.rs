@@ -0,0 +1,138 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde_json::{Map, Value};
+use std::io::Write;
+use time::format_description::well_known::Iso8601;
+use time::OffsetDateTime;
+use tracing::field::{Field, Visit};
+use tracing::span::Attributes;
+use tracing::{info, info_span, Event, Id, Subscriber};
+use tracing_json_span_fields::{Error, JsonLayer, JsonOutput, JsonWriter};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+/// Writes events the way the layer did before records were built from borrowed fields: the
+/// fields of every span are cloned into a new map for each event, which is converted into a
+/// [`Value`] and then serialized.
+struct BaselineLayer;
+
+struct BaselineFields(Map<String, Value>);
+
+struct BaselineVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for BaselineVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for BaselineLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut BaselineVisitor(&mut fields));
+        let span = ctx.span(id).unwrap();
+        span.extensions_mut().insert(BaselineFields(fields));
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                let span_fields = &extensions.get::<BaselineFields>().unwrap().0;
+                for (key, value) in span_fields {
+                    fields.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        event.record(&mut BaselineVisitor(&mut fields));
+
+        let metadata = event.metadata();
+        fields.insert("target".to_string(), metadata.target().into());
+        fields.insert("name".to_string(), metadata.name().into());
+        fields.insert("log_level".to_string(), metadata.level().as_str().into());
+        let timestamp = OffsetDateTime::now_utc().format(&Iso8601::DEFAULT);
+        fields.insert("timestamp".to_string(), timestamp.unwrap().into());
+
+        let mut line = serde_json::to_vec(&Value::Object(fields)).unwrap();
+        line.push(b'\n');
+        std::io::sink().write_all(&line).unwrap();
+    }
+}
+
+/// Writes events to a sink like [`JsonWriter`], but through [`JsonOutput::write`], so that a
+/// [`Value`] is built for every event.
+struct ValueSink;
+
+impl JsonOutput for ValueSink {
+    fn write(&self, value: Value) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&value)?;
+        line.push(b'\n');
+        std::io::sink().write_all(&line)?;
+        Ok(())
+    }
+}
+
+fn log_in_spans<S>(c: &mut Criterion, name: &str, subscriber: S)
+where
+    S: Subscriber + Send + Sync + 'static,
+{
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let outer = info_span!(
+        "request",
+        request_id = "4d8a0ab2",
+        user_id = 42,
+        path = "/api/items"
+    );
+    let _outer = outer.enter();
+    let inner = info_span!("query", table = "items", rows = 100_u64, cached = false);
+    let _inner = inner.enter();
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            info!(
+                elapsed_ms = 12.5,
+                status = 200,
+                body = "a somewhat longer string field with \"quotes\" to escape",
+                "request finished"
+            )
+        })
+    });
+}
+
+fn serialization(c: &mut Criterion) {
+    log_in_spans(c, "baseline", Registry::default().with(BaselineLayer));
+    let layer = JsonLayer::default().with_output(ValueSink);
+    log_in_spans(c, "event via write", Registry::default().with(layer));
+    let layer = JsonLayer::default().with_output(JsonWriter::new(std::io::sink));
+    log_in_spans(c, "event via write_bytes", Registry::default().with(layer));
+}
+
+criterion_group!(benches, serialization);
+criterion_main!(benches);
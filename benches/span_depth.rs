@@ -0,0 +1,32 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tracing::{info, info_span, Span};
+use tracing_json_span_fields::{JsonLayer, JsonWriter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::Registry;
+
+/// Creates `depth` nested spans, each with a few fields, and returns the innermost one.
+fn nested_spans(depth: usize) -> Span {
+    let mut span = Span::none();
+    for level in 0..depth {
+        span = info_span!(parent: &span, "level", level, kind = "nested", id = level * 100);
+    }
+    span
+}
+
+fn span_depth(c: &mut Criterion) {
+    let layer = JsonLayer::default().with_output(JsonWriter::new(std::io::sink));
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    let mut group = c.benchmark_group("event in nested spans");
+    for depth in [1, 10] {
+        let span = nested_spans(depth);
+        let _entered = span.enter();
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &depth, |b, _| {
+            b.iter(|| info!(status = 200, "request finished"))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, span_depth);
+criterion_main!(benches);
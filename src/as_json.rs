@@ -0,0 +1,99 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::Cell;
+use std::fmt;
+
+/// Wraps a value to be written as nested JSON by a [`JsonLayer`](crate::JsonLayer), instead
+/// of as a string, when recorded using `Debug`.
+///
+/// Other subscribers see the value formatted as JSON. If serializing the value fails, the
+/// error is written as a string instead.
+///
+/// ```
+/// use tracing::info;
+/// use tracing_json_span_fields::AsJson;
+/// let body = serde_json::json!({"event": "push", "commits": 2});
+/// info!(body = ?AsJson(&body), "Webhook received");
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct AsJson<T>(pub T);
+
+thread_local! {
+    /// Whether a field is being recorded by a `JsonLayer`
+    static CAPTURING: Cell<bool> = const { Cell::new(false) };
+    /// The value of the last `AsJson` formatted while capturing, and the length of its text
+    static CAPTURED: Cell<Option<(Value, usize)>> = const { Cell::new(None) };
+}
+
+impl<T: Serialize> fmt::Debug for AsJson<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match serde_json::to_value(&self.0) {
+            Ok(value) => {
+                let text = value.to_string();
+                f.write_str(&text)?;
+                if CAPTURING.with(Cell::get) {
+                    CAPTURED.with(|captured| captured.set(Some((value, text.len()))));
+                }
+                Ok(())
+            }
+            Err(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Formats `value` using `Debug`, and returns the JSON if it's an [`AsJson`], or the text
+/// otherwise.
+pub(crate) fn debug_value(value: &dyn fmt::Debug) -> Value {
+    let capturing = CAPTURING.with(|capturing| capturing.replace(true));
+    let text = format!("{:?}", value);
+    CAPTURING.with(|flag| flag.set(capturing));
+    match CAPTURED.with(Cell::take) {
+        // An `AsJson` nested in another value doesn't make up the whole text
+        Some((json, len)) if len == text.len() => json,
+        _ => text.into(),
+    }
+}
+
+/// Parses the value of a field with a name ending with the JSON suffix, keeping the string if
+/// it isn't valid JSON.
+pub(crate) fn parse_json(value: Value) -> Value {
+    match value {
+        Value::String(s) => serde_json::from_str(&s).unwrap_or(Value::String(s)),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug)]
+    struct Wrapper {
+        #[allow(dead_code)]
+        inner: AsJson<Vec<u8>>,
+    }
+
+    #[test]
+    fn captured_values() {
+        assert_eq!(
+            json!({"a": [1, 2]}),
+            debug_value(&AsJson(json!({"a": [1, 2]})))
+        );
+        assert_eq!("[1,2]", format!("{:?}", AsJson([1, 2])));
+        assert_eq!(
+            json!("Wrapper { inner: [1] }"),
+            debug_value(&Wrapper {
+                inner: AsJson(vec![1])
+            })
+        );
+        assert_eq!(json!("\"plain\""), debug_value(&"plain"));
+    }
+
+    #[test]
+    fn parsed_values() {
+        assert_eq!(json!({"a": 1}), parse_json(json!(r#"{"a": 1}"#)));
+        assert_eq!(json!("{not json"), parse_json(json!("{not json")));
+        assert_eq!(json!(42), parse_json(json!(42)));
+    }
+}
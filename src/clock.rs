@@ -0,0 +1,107 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+/// A source of the current time for the timestamps and span timings written by a
+/// [`JsonLayer`](crate::JsonLayer).
+///
+/// Replacing the default [`SystemClock`] with a [`FixedClock`] or [`SteppingClock`] makes the
+/// output deterministic, e.g. for snapshot tests.
+pub trait Clock {
+    /// The current time, used for timestamps.
+    fn now(&self) -> OffsetDateTime;
+
+    /// A monotonic reading, used to measure how long spans take. Only the differences between
+    /// readings are meaningful.
+    fn instant(&self) -> Duration;
+}
+
+/// The default [`Clock`] using the current system time in UTC, and [`Instant`] for span
+/// timings so that these are not affected by changes to the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+
+    fn instant(&self) -> Duration {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed()
+    }
+}
+
+/// A [`Clock`] always returning the same time, so that all spans take no time.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub OffsetDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> OffsetDateTime {
+        self.0
+    }
+
+    fn instant(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// A [`Clock`] starting at a given time and advancing by a fixed step every time it is read.
+///
+/// The time and the monotonic reading advance separately, each starting from the first
+/// reading, so that span timings only depend on how often the layer measures them.
+#[derive(Debug)]
+pub struct SteppingClock {
+    next: Mutex<OffsetDateTime>,
+    next_instant: Mutex<Duration>,
+    step: time::Duration,
+}
+
+impl SteppingClock {
+    pub fn new(start: OffsetDateTime, step: time::Duration) -> Self {
+        SteppingClock {
+            next: Mutex::new(start),
+            next_instant: Mutex::new(Duration::ZERO),
+            step,
+        }
+    }
+}
+
+impl Clock for SteppingClock {
+    fn now(&self) -> OffsetDateTime {
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let now = *next;
+        *next += self.step;
+        now
+    }
+
+    fn instant(&self) -> Duration {
+        let mut next = self.next_instant.lock().unwrap_or_else(|e| e.into_inner());
+        let instant = *next;
+        // Monotonic readings can't go backwards
+        *next += Duration::try_from(self.step).unwrap_or_default();
+        instant
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn stepping_clock() {
+        let clock = SteppingClock::new(datetime!(2023-07-25 10:00 UTC), time::Duration::SECOND);
+        assert_eq!(datetime!(2023-07-25 10:00:00 UTC), clock.now());
+        assert_eq!(Duration::ZERO, clock.instant());
+        assert_eq!(datetime!(2023-07-25 10:00:01 UTC), clock.now());
+        assert_eq!(Duration::from_secs(1), clock.instant());
+        assert_eq!(datetime!(2023-07-25 10:00:02 UTC), clock.now());
+    }
+
+    #[test]
+    fn system_clock_is_monotonic() {
+        let first = SystemClock.instant();
+        assert!(SystemClock.instant() >= first);
+    }
+}
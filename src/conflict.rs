@@ -0,0 +1,369 @@
+use crate::record::{FnvBuildHasher, JsonRecord};
+use indexmap::IndexMap;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// How fields with the same name in several spans and the event are combined when using
+/// [`SpanFormat::Flat`](crate::SpanFormat::Flat).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The value of the event, or of the innermost span, is used.
+    #[default]
+    InnermostWins,
+    /// The global value, or the value of the outermost span, or of the event if no span has the
+    /// field, is used.
+    OutermostWins,
+    /// The innermost value is used, and the values of outer spans are kept as
+    /// `span.<span name>.<field name>`, and global fields as `global.<field name>`. The values
+    /// of several outer spans with the same name are collected into an array, ordered from the
+    /// outermost span.
+    Prefix,
+    /// All values are collected into an array, ordered from the global value to the event.
+    Collect,
+}
+
+/// What happens to fields of spans and events named like one of the fields added by a
+/// [`JsonLayer`](crate::JsonLayer), such as `timestamp`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReservedFieldPolicy {
+    /// The field is replaced by the one added by the layer.
+    #[default]
+    Overwrite,
+    /// The field is moved into a `fields` object.
+    Nest,
+}
+
+/// The name of the object holding fields moved by [`ReservedFieldPolicy::Nest`].
+const NESTED_FIELDS: &str = "fields";
+
+/// Where the value of a field came from.
+#[derive(Clone, Copy)]
+enum Origin<'a> {
+    Global,
+    Span(&'a str),
+    Event,
+}
+
+/// A field of a span or of one of its ancestors.
+#[derive(Clone, Debug)]
+struct SpanField {
+    value: Value,
+    /// The name of the innermost span with the field
+    span_name: &'static str,
+    /// Whether the value is an array of the values of several spans, with
+    /// [`ConflictPolicy::Collect`]
+    collected: bool,
+}
+
+/// The fields of a span and its ancestors, combined according to a [`ConflictPolicy`] when the
+/// span is created, so that writing an event only needs to add the global fields and the fields
+/// of the event.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SpanFields {
+    fields: IndexMap<String, SpanField, FnvBuildHasher>,
+}
+
+impl SpanFields {
+    /// Combines the fields of a span with the combined fields of its parent.
+    pub(crate) fn new(
+        policy: ConflictPolicy,
+        parent: Option<&SpanFields>,
+        span_name: &'static str,
+        fields: &Map<String, Value>,
+    ) -> Self {
+        let mut merged = parent.cloned().unwrap_or_default();
+        for (key, value) in fields {
+            let field = SpanField {
+                value: value.clone(),
+                span_name,
+                collected: false,
+            };
+            let Some(outer) = merged.fields.get_mut(key) else {
+                merged.fields.insert(key.clone(), field);
+                continue;
+            };
+            match policy {
+                ConflictPolicy::InnermostWins => *outer = field,
+                ConflictPolicy::OutermostWins => {}
+                ConflictPolicy::Prefix => {
+                    let shadowed = std::mem::replace(outer, field);
+                    let prefixed = format!("span.{}.{}", shadowed.span_name, key);
+                    // Spans with the same name, such as recursive ones, keep all their values
+                    match merged.fields.get_mut(&prefixed) {
+                        Some(existing) => {
+                            collect(
+                                &mut existing.value,
+                                existing.collected,
+                                shadowed.value,
+                                false,
+                            );
+                            existing.collected = true;
+                        }
+                        None => {
+                            merged.fields.insert(prefixed, shadowed);
+                        }
+                    }
+                }
+                ConflictPolicy::Collect => {
+                    collect(&mut outer.value, outer.collected, field.value, false);
+                    outer.span_name = span_name;
+                    outer.collected = true;
+                }
+            }
+        }
+        merged
+    }
+}
+
+/// Adds `value` to the values collected in `values`, each of which is an array of collected
+/// values if flagged, or a single value.
+fn collect(values: &mut Value, collected: bool, value: Value, value_collected: bool) {
+    let mut all = match (collected, values.take()) {
+        (true, Value::Array(all)) => all,
+        (_, single) => vec![single],
+    };
+    match (value_collected, value) {
+        (true, Value::Array(values)) => all.extend(values),
+        (_, single) => all.push(single),
+    }
+    *values = Value::Array(all);
+}
+
+/// Combines the global fields, the combined fields of the spans and the fields of the event
+/// according to a [`ConflictPolicy`].
+pub(crate) struct FieldMerger<'a> {
+    policy: ConflictPolicy,
+    fields: JsonRecord<'a>,
+    /// Where the values of the global fields, and of fields with values from several places,
+    /// came from, and whether they are collected arrays. Other fields come from `spans`, or are
+    /// only in the event.
+    origins: HashMap<&'a str, (Origin<'a>, bool)>,
+    spans: Option<&'a SpanFields>,
+}
+
+impl<'a> FieldMerger<'a> {
+    pub(crate) fn new(policy: ConflictPolicy) -> Self {
+        FieldMerger {
+            policy,
+            fields: JsonRecord::new(),
+            origins: HashMap::new(),
+            spans: None,
+        }
+    }
+
+    /// Adds a field added to all records, before the spans are added.
+    pub(crate) fn add_global(&mut self, key: &'a str, value: Cow<'a, Value>) {
+        if !self.fields.contains_key(key) {
+            self.origins.insert(key, (Origin::Global, false));
+        }
+        self.add(key, Origin::Global, value, false);
+    }
+
+    /// Adds the combined fields of the spans, before the event is added.
+    pub(crate) fn add_spans(&mut self, spans: &'a SpanFields) {
+        self.spans = Some(spans);
+        for (key, field) in &spans.fields {
+            let value = Cow::Borrowed(&field.value);
+            self.add(key, Origin::Span(field.span_name), value, field.collected);
+        }
+    }
+
+    /// Adds the fields of the event.
+    pub(crate) fn add_event(&mut self, fields: &'a Map<String, Value>) {
+        for (key, value) in fields {
+            self.add(key, Origin::Event, Cow::Borrowed(value), false);
+        }
+    }
+
+    fn add(&mut self, key: &'a str, origin: Origin<'a>, value: Cow<'a, Value>, collected: bool) {
+        let Some(existing) = self.fields.get_mut(key) else {
+            self.fields.insert(key, value);
+            return;
+        };
+        match self.policy {
+            ConflictPolicy::InnermostWins => *existing = value,
+            ConflictPolicy::OutermostWins => {}
+            ConflictPolicy::Prefix | ConflictPolicy::Collect => {
+                // Only fields with values from several places need to know where these came
+                // from. Fields missing from both were added with a prefix.
+                let (existing_origin, existing_collected) = match self.origins.get(key) {
+                    Some(&state) => state,
+                    None => match self.spans.and_then(|spans| spans.fields.get(key)) {
+                        Some(field) => (Origin::Span(field.span_name), field.collected),
+                        None => (Origin::Global, false),
+                    },
+                };
+                if self.policy == ConflictPolicy::Prefix {
+                    let shadowed = std::mem::replace(existing, value);
+                    let prefixed = match existing_origin {
+                        Origin::Global => format!("global.{}", key),
+                        Origin::Span(span_name) => format!("span.{}.{}", span_name, key),
+                        Origin::Event => unreachable!("the fields of the event are added last"),
+                    };
+                    // The prefixed field is already there for spans with the same name
+                    match self.fields.get_mut(&prefixed) {
+                        Some(values) => {
+                            let collected = self
+                                .spans
+                                .and_then(|spans| spans.fields.get(&prefixed))
+                                .is_some_and(|field| field.collected);
+                            collect(values.to_mut(), collected, shadowed.into_owned(), false);
+                        }
+                        None => self.fields.insert(prefixed, shadowed),
+                    }
+                    self.origins.insert(key, (origin, false));
+                } else {
+                    let values = existing.to_mut();
+                    collect(values, existing_collected, value.into_owned(), collected);
+                    self.origins.insert(key, (origin, true));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> JsonRecord<'a> {
+        self.fields
+    }
+}
+
+/// Moves the `reserved` fields into a nested object to keep them from being overwritten.
+pub(crate) fn nest_reserved_fields(fields: &mut JsonRecord<'_>, reserved: &[&str]) {
+    let mut nested = Map::new();
+    for key in reserved {
+        if let Some(value) = fields.remove(key) {
+            nested.insert(key.to_string(), value.into_owned());
+        }
+    }
+    if nested.is_empty() {
+        return;
+    }
+
+    // A field already using the name of the nested object is moved into it as well
+    if let Some(value) = fields.remove(NESTED_FIELDS) {
+        nested.insert(NESTED_FIELDS.to_string(), value.into_owned());
+    }
+    fields.insert(NESTED_FIELDS, Cow::Owned(nested.into()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn merge(policy: ConflictPolicy) -> Value {
+        let outer = json!({"a": 1, "outer": true});
+        let inner = json!({"a": 2, "b": 2});
+        let event = json!({"b": 3, "c": 3});
+
+        let outer = SpanFields::new(policy, None, "outer", outer.as_object().unwrap());
+        let inner = SpanFields::new(policy, Some(&outer), "inner", inner.as_object().unwrap());
+        let mut merger = FieldMerger::new(policy);
+        merger.add_global("a", Cow::Owned(json!(0)));
+        merger.add_spans(&inner);
+        merger.add_event(event.as_object().unwrap());
+        merger.finish().into_map().into()
+    }
+
+    #[test]
+    fn innermost_wins() {
+        assert_eq!(
+            json!({"a": 2, "b": 3, "c": 3, "outer": true}),
+            merge(ConflictPolicy::InnermostWins)
+        );
+    }
+
+    #[test]
+    fn outermost_wins() {
+        assert_eq!(
+            json!({"a": 0, "b": 2, "c": 3, "outer": true}),
+            merge(ConflictPolicy::OutermostWins)
+        );
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(
+            json!({
+                "a": 2,
+                "global.a": 0,
+                "span.outer.a": 1,
+                "b": 3,
+                "span.inner.b": 2,
+                "c": 3,
+                "outer": true,
+            }),
+            merge(ConflictPolicy::Prefix)
+        );
+    }
+
+    #[test]
+    fn prefix_spans_with_the_same_name() {
+        let policy = ConflictPolicy::Prefix;
+        let first = json!({"x": 1});
+        let second = json!({"x": 2});
+        let third = json!({"x": 3});
+        let first = SpanFields::new(policy, None, "outer", first.as_object().unwrap());
+        let second = SpanFields::new(policy, Some(&first), "outer", second.as_object().unwrap());
+        let third = SpanFields::new(policy, Some(&second), "outer", third.as_object().unwrap());
+        let inner = json!({"x": 4});
+        let inner = SpanFields::new(policy, Some(&third), "inner", inner.as_object().unwrap());
+        let event = json!({"x": 5});
+
+        let mut merger = FieldMerger::new(policy);
+        merger.add_spans(&inner);
+        merger.add_event(event.as_object().unwrap());
+        assert_eq!(
+            json!({"x": 5, "span.inner.x": 4, "span.outer.x": [1, 2, 3]}),
+            Value::from(merger.finish().into_map())
+        );
+
+        let mut merger = FieldMerger::new(policy);
+        merger.add_spans(&second);
+        merger.add_event(event.as_object().unwrap());
+        assert_eq!(
+            json!({"x": 5, "span.outer.x": [1, 2]}),
+            Value::from(merger.finish().into_map())
+        );
+    }
+
+    #[test]
+    fn collect() {
+        assert_eq!(
+            json!({"a": [0, 1, 2], "b": [2, 3], "c": 3, "outer": true}),
+            merge(ConflictPolicy::Collect)
+        );
+    }
+
+    #[test]
+    fn collect_keeps_arrays_of_one_span() {
+        let outer = json!({"a": [1, 2]});
+        let inner = json!({"a": 3});
+        let policy = ConflictPolicy::Collect;
+        let outer = SpanFields::new(policy, None, "outer", outer.as_object().unwrap());
+        let inner = SpanFields::new(policy, Some(&outer), "inner", inner.as_object().unwrap());
+        let event = json!({"a": [4]});
+
+        let mut merger = FieldMerger::new(policy);
+        merger.add_spans(&inner);
+        merger.add_event(event.as_object().unwrap());
+        assert_eq!(
+            json!({"a": [[1, 2], 3, [4]]}),
+            Value::from(merger.finish().into_map())
+        );
+    }
+
+    #[test]
+    fn nest_reserved() {
+        let values = json!({"timestamp": "mine", "fields": 1, "other": 2});
+        let mut fields = JsonRecord::new();
+        for (key, value) in values.as_object().unwrap() {
+            fields.insert(key.as_str(), Cow::Borrowed(value));
+        }
+        nest_reserved_fields(&mut fields, &["timestamp", "target"]);
+        assert_eq!(
+            json!({"fields": {"timestamp": "mine", "fields": 1}, "other": 2}),
+            Value::from(fields.into_map())
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Errors that can occur while a [`JsonLayer`](crate::JsonLayer) is producing output.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Writing the output failed
+    Io(std::io::Error),
+    /// Serializing the output failed
+    Json(serde_json::Error),
+    /// Formatting the timestamp failed
+    Timestamp(time::error::Format),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "failed to write output: {}", e),
+            Error::Json(e) => write!(f, "failed to serialize output: {}", e),
+            Error::Timestamp(e) => write!(f, "failed to format timestamp: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Timestamp(e) => Some(e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<time::error::Format> for Error {
+    fn from(e: time::error::Format) -> Self {
+        Error::Timestamp(e)
+    }
+}
+
+pub(crate) type ErrorHandler = Box<dyn Fn(Error) + Send + Sync>;
+
+/// The default error handler writing at most one line per second to stderr.
+pub(crate) fn default_error_handler() -> ErrorHandler {
+    let reporter = RateLimitedReporter::new(Duration::from_secs(1));
+    Box::new(move |error| reporter.report(&error, &mut std::io::stderr()))
+}
+
+/// Writes at most one error per interval, counting the errors suppressed in between.
+struct RateLimitedReporter {
+    interval: Duration,
+    last_report: Mutex<Option<Instant>>,
+    suppressed: AtomicUsize,
+}
+
+impl RateLimitedReporter {
+    fn new(interval: Duration) -> Self {
+        RateLimitedReporter {
+            interval,
+            last_report: Mutex::new(None),
+            suppressed: AtomicUsize::new(0),
+        }
+    }
+
+    fn report(&self, error: &Error, out: &mut impl Write) {
+        let now = Instant::now();
+        let mut last_report = match self.last_report.try_lock() {
+            Ok(last_report) => last_report,
+            // Someone else is reporting right now, or panicked while doing so
+            Err(_) => {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        if matches!(*last_report, Some(last) if now.duration_since(last) < self.interval) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        *last_report = Some(now);
+
+        let suppressed = self.suppressed.swap(0, Ordering::Relaxed);
+        // Nothing sensible can be done if even reporting fails
+        let _ = if suppressed > 0 {
+            writeln!(
+                out,
+                "tracing_json_span_fields: {} ({} similar errors suppressed)",
+                error, suppressed
+            )
+        } else {
+            writeln!(out, "tracing_json_span_fields: {}", error)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limited_reports_are_counted() {
+        let reporter = RateLimitedReporter::new(Duration::from_secs(3600));
+        let mut out = Vec::new();
+
+        reporter.report(&Error::Io(std::io::ErrorKind::BrokenPipe.into()), &mut out);
+        assert_eq!(0, reporter.suppressed.load(Ordering::Relaxed));
+
+        reporter.report(&Error::Io(std::io::ErrorKind::BrokenPipe.into()), &mut out);
+        reporter.report(&Error::Io(std::io::ErrorKind::BrokenPipe.into()), &mut out);
+        assert_eq!(2, reporter.suppressed.load(Ordering::Relaxed));
+        assert_eq!(
+            "tracing_json_span_fields: failed to write output: broken pipe\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn suppressed_reports_are_written_with_the_next_report() {
+        let reporter = RateLimitedReporter::new(Duration::ZERO);
+        reporter.suppressed.store(3, Ordering::Relaxed);
+        let mut out = Vec::new();
+
+        reporter.report(&Error::Io(std::io::ErrorKind::BrokenPipe.into()), &mut out);
+        assert_eq!(0, reporter.suppressed.load(Ordering::Relaxed));
+        assert_eq!(
+            "tracing_json_span_fields: failed to write output: broken pipe (3 similar errors suppressed)\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
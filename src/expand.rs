@@ -0,0 +1,159 @@
+use crate::record::JsonRecord;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+
+/// The key of the value of a field named like a prefix of other fields, within the object
+/// holding the other fields.
+const LEAF_VALUE: &str = "_value";
+
+/// Expands fields with dotted names, such as `http.method`, into nested objects, such as
+/// `{"http": {"method": ...}}`.
+///
+/// Names ending with `json_suffix`, such as `headers.json`, are expanded without the suffix,
+/// unless that would leave nothing. Names with empty parts, such as `a..b`, are kept as they
+/// are. When a name is both a field and a prefix of other fields, the value of the field is
+/// moved into the object as `_value`. Objects are merged, and of two values at the same place,
+/// the one added later wins.
+pub(crate) fn expand_dotted_names<'a>(
+    fields: JsonRecord<'a>,
+    json_suffix: Option<&str>,
+) -> JsonRecord<'a> {
+    let mut expanded = JsonRecord::new();
+    for (key, value) in fields {
+        let key = match json_suffix.and_then(|suffix| key.strip_suffix(suffix)) {
+            Some(stripped) if !stripped.is_empty() => Cow::Owned(stripped.to_string()),
+            _ => key,
+        };
+        let nested = match key.split_once('.') {
+            Some((first, rest)) if !key.split('.').any(str::is_empty) => {
+                Some((first.to_string(), rest))
+            }
+            _ => None,
+        };
+        match nested {
+            None => match expanded.get_mut(&key) {
+                Some(existing) => merge(existing.to_mut(), value.into_owned()),
+                None => expanded.insert(key, value),
+            },
+            Some((first, rest)) => {
+                let value = rest.rsplit('.').fold(value.into_owned(), |value, part| {
+                    Value::Object(Map::from_iter([(part.to_string(), value)]))
+                });
+                match expanded.get_mut(&first) {
+                    Some(existing) => merge(existing.to_mut(), value),
+                    None => expanded.insert(first, Cow::Owned(value)),
+                }
+            }
+        }
+    }
+    expanded
+}
+
+/// Merges `value` into `existing`, keeping leaf values in objects as [`LEAF_VALUE`].
+fn merge(existing: &mut Value, value: Value) {
+    match (existing, value) {
+        (Value::Object(existing), Value::Object(fields)) => {
+            for (key, value) in fields {
+                match existing.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        existing.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Object(existing), leaf) => {
+            merge_field(existing, leaf);
+        }
+        (existing, Value::Object(mut fields)) => {
+            let leaf = existing.take();
+            merge_field(&mut fields, leaf);
+            *existing = Value::Object(fields);
+        }
+        (existing, leaf) => *existing = leaf,
+    }
+}
+
+/// Merges a leaf value into `fields` as [`LEAF_VALUE`].
+fn merge_field(fields: &mut Map<String, Value>, leaf: Value) {
+    match fields.get_mut(LEAF_VALUE) {
+        Some(existing) => merge(existing, leaf),
+        None => {
+            fields.insert(LEAF_VALUE.to_string(), leaf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn expand_with_suffix(fields: &[(&str, Value)], json_suffix: Option<&str>) -> Value {
+        let mut record = JsonRecord::new();
+        for (key, value) in fields {
+            record.insert(*key, Cow::Borrowed(value));
+        }
+        expand_dotted_names(record, json_suffix).into_map().into()
+    }
+
+    fn expand(fields: &[(&str, Value)]) -> Value {
+        expand_with_suffix(fields, None)
+    }
+
+    #[test]
+    fn nested_objects() {
+        assert_eq!(
+            json!({"http": {"method": "GET", "response": {"status_code": 200}}, "plain": 1}),
+            expand(&[
+                ("http.method", json!("GET")),
+                ("plain", json!(1)),
+                ("http.response.status_code", json!(200)),
+            ])
+        );
+        assert_eq!(
+            json!({"a..b": 1, ".c": 2, "d.": 3}),
+            expand(&[("a..b", json!(1)), (".c", json!(2)), ("d.", json!(3))])
+        );
+    }
+
+    #[test]
+    fn leaves_and_objects() {
+        let expected = json!({"http": {"_value": "x", "method": "GET"}});
+        assert_eq!(
+            expected,
+            expand(&[("http", json!("x")), ("http.method", json!("GET"))])
+        );
+        assert_eq!(
+            expected,
+            expand(&[("http.method", json!("GET")), ("http", json!("x"))])
+        );
+        assert_eq!(
+            json!({"http": {"method": "GET", "version": 2}}),
+            expand(&[
+                ("http", json!({"version": 2})),
+                ("http.method", json!("GET")),
+            ])
+        );
+        assert_eq!(
+            json!({"a": {"b": 2}}),
+            expand(&[("a.b", json!(1)), ("a", json!({"b": 2}))]),
+            "the later value wins"
+        );
+    }
+
+    #[test]
+    fn json_suffix_is_stripped() {
+        assert_eq!(
+            json!({"headers": {"accept": "*/*"}, "http": {"body": [1]}, ".json": 2}),
+            expand_with_suffix(
+                &[
+                    ("headers.json", json!({"accept": "*/*"})),
+                    ("http.body.json", json!([1])),
+                    (".json", json!(2)),
+                ],
+                Some(".json")
+            )
+        );
+    }
+}
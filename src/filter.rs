@@ -0,0 +1,148 @@
+//! Choosing which fields of spans and events are written, depending on their target.
+
+use crate::redact::FieldPattern;
+
+/// Whether a rule of a [`FieldFilter`] applies to the fields of spans, of events, or both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldScope {
+    #[default]
+    All,
+    Spans,
+    Events,
+}
+
+impl FieldScope {
+    fn includes(self, span: bool) -> bool {
+        match self {
+            FieldScope::All => true,
+            FieldScope::Spans => span,
+            FieldScope::Events => !span,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    target_prefix: String,
+    scope: FieldScope,
+    allow: bool,
+    fields: Vec<FieldPattern>,
+}
+
+/// Rules for dropping fields of spans and events with a target starting with a prefix.
+///
+/// A field is dropped if it matches a deny rule, or if there are allow rules for the target and
+/// it matches none of them. The message of events is only dropped by deny rules.
+///
+/// ```
+/// use tracing_json_span_fields::filter::{FieldFilter, FieldScope};
+/// use tracing_json_span_fields::redact::FieldPattern;
+/// use tracing_json_span_fields::JsonLayer;
+/// let layer = JsonLayer::default().with_field_filter(
+///     FieldFilter::new()
+///         .deny("sqlx", FieldScope::Events, [FieldPattern::exact("sql.params")])
+///         .allow(
+///             "noisy_library",
+///             FieldScope::Spans,
+///             [FieldPattern::exact("user_id"), FieldPattern::exact("request_id")],
+///         ),
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FieldFilter {
+    rules: Vec<Rule>,
+}
+
+impl FieldFilter {
+    pub fn new() -> Self {
+        FieldFilter::default()
+    }
+
+    /// Keeps only the fields matching `fields`, and those allowed by other rules, for targets
+    /// starting with `target_prefix`.
+    pub fn allow(
+        self,
+        target_prefix: impl Into<String>,
+        scope: FieldScope,
+        fields: impl IntoIterator<Item = FieldPattern>,
+    ) -> FieldFilter {
+        self.rule(target_prefix.into(), scope, true, fields)
+    }
+
+    /// Drops the fields matching `fields` for targets starting with `target_prefix`.
+    pub fn deny(
+        self,
+        target_prefix: impl Into<String>,
+        scope: FieldScope,
+        fields: impl IntoIterator<Item = FieldPattern>,
+    ) -> FieldFilter {
+        self.rule(target_prefix.into(), scope, false, fields)
+    }
+
+    fn rule(
+        mut self,
+        target_prefix: String,
+        scope: FieldScope,
+        allow: bool,
+        fields: impl IntoIterator<Item = FieldPattern>,
+    ) -> FieldFilter {
+        self.rules.push(Rule {
+            target_prefix,
+            scope,
+            allow,
+            fields: fields.into_iter().collect(),
+        });
+        self
+    }
+
+    /// Whether the field `name` of a span, or an event, with the `target` is written.
+    pub(crate) fn keeps(&self, target: &str, span: bool, name: &str) -> bool {
+        let mut allow_rules = false;
+        let mut allowed = false;
+        for rule in &self.rules {
+            if !rule.scope.includes(span) || !target.starts_with(&rule.target_prefix) {
+                continue;
+            }
+            let matches = rule.fields.iter().any(|pattern| pattern.matches(name));
+            if rule.allow {
+                allow_rules = true;
+                allowed |= matches;
+            } else if matches {
+                return false;
+            }
+        }
+        allowed || !allow_rules || (!span && name == "message")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allow_and_deny() {
+        let filter = FieldFilter::new()
+            .deny("sqlx", FieldScope::Events, [FieldPattern::exact("params")])
+            .allow("noisy", FieldScope::All, [FieldPattern::exact("user_id")])
+            .allow(
+                "noisy",
+                FieldScope::Spans,
+                [FieldPattern::glob("request_*")],
+            )
+            .deny("", FieldScope::All, [FieldPattern::exact("secret")]);
+
+        assert!(!filter.keeps("sqlx::query", false, "params"));
+        assert!(filter.keeps("sqlx::query", true, "params"));
+        assert!(filter.keeps("sqlx::query", false, "rows"));
+
+        assert!(filter.keeps("noisy::client", true, "user_id"));
+        assert!(filter.keeps("noisy::client", true, "request_id"));
+        assert!(!filter.keeps("noisy::client", true, "retries"));
+        assert!(filter.keeps("noisy::client", false, "user_id"));
+        assert!(!filter.keeps("noisy::client", false, "request_id"));
+        assert!(filter.keeps("noisy::client", false, "message"));
+
+        assert!(filter.keeps("my_app", false, "anything"));
+        assert!(!filter.keeps("my_app", false, "secret"));
+    }
+}
@@ -47,31 +47,293 @@
 //! {"log_level":"ERROR","logged_message_field":"value","message":"Logged message","name":"event src/main.rs:123","target":"tracing_json","timestamp":"10:02:01.9"}
 //! ```
 //!
+//! Timestamps can also be written as numbers using [`timestamp::Epoch`], or with another offset
+//! than UTC using [`timestamp::WithOffset`] or [`timestamp::Local`].
+//!
+//! ### Writing to other outputs
+//!
+//! Any [`MakeWriter`], such as stderr or a file, can be used as output with [`JsonWriter`].
+//!
+//! ```
+//! use tracing::info;
+//! use tracing_subscriber::prelude::*;
+//! use tracing_json_span_fields::{JsonLayer, JsonWriter};
+//! tracing_subscriber::registry().with(JsonLayer::default().with_output(JsonWriter::new(std::io::stderr))).init();
+//! info!(logged_message_field = "value", "Logged message");
+//! ```
+//!
+//! ### Field order
+//!
+//! Fields are written in alphabetical order. With the `preserve_order` feature, the fields
+//! added by the layer, such as `timestamp`, `log_level` and `message`, come first, followed by
+//! the fields of spans from the outermost to the innermost span, and then the fields of the
+//! event in the order they are declared.
+//!
+//! ### Structured values
+//!
+//! With the `valuable` feature, and `--cfg tracing_unstable` in `RUSTFLAGS` as required by
+//! tracing, fields recorded as [`valuable`](https://docs.rs/valuable) values are written as
+//! nested objects and arrays instead of strings. Structs become objects, lists and tuples
+//! become arrays, and enums become the name of the variant, or an object with the variant as
+//! the only key, like serde would serialize them. Without the cfg, the feature has no effect
+//! and the values are recorded using `Debug` like any other value.
+//!
+//! ### JSON values
+//!
+//! Values that are already JSON, or can be serialized with serde, can be written as nested JSON
+//! by recording them wrapped in [`AsJson`] using `?`, or as strings in fields with a name ending
+//! with the suffix set with [`JsonLayer::with_json_field_suffix`].
+//!
+//! ```
+//! use tracing::info;
+//! use tracing_subscriber::prelude::*;
+//! use tracing_json_span_fields::{AsJson, JsonLayer};
+//! tracing_subscriber::registry().with(JsonLayer::default().with_json_field_suffix(".json")).init();
+//! let payload = serde_json::json!({"event": "push"});
+//! info!(payload = ?AsJson(&payload), "headers.json" = r#"{"accept": "*/*"}"#, "Webhook received");
+//! ```
+//!
+//! ### Dotted names
+//!
+//! Fields named like `http.method` and `http.status_code` can be written as nested objects,
+//! like `{"http": {"method": "GET", "status_code": 200}}`, using
+//! [`JsonLayer::with_expanded_dotted_names`]. A field named like a prefix of other fields, such
+//! as `http`, is then written as `_value` in the object. The suffix of JSON fields is removed
+//! before expanding, so `headers.json` is written as `headers`.
+//!
 //! ## Thanks
 //!
 //! * <https://burgers.io/custom-logging-in-rust-using-tracing>
 
+mod as_json;
+pub mod clock;
+mod conflict;
+mod error;
+mod expand;
+pub mod filter;
+mod limits;
+pub mod non_blocking;
+#[cfg(feature = "pseudonymize")]
+pub mod pseudonymize;
+mod record;
+pub mod redact;
+pub mod resource;
+pub mod rotating;
+#[cfg(all(tracing_unstable, feature = "valuable"))]
+mod structured;
+pub mod timestamp;
+
+pub use as_json::AsJson;
+pub use conflict::{ConflictPolicy, ReservedFieldPolicy};
+pub use error::Error;
+pub use limits::{Limits, OversizePolicy};
+
+use clock::{Clock, SystemClock};
+use conflict::{nest_reserved_fields, FieldMerger, SpanFields};
+use error::{default_error_handler, ErrorHandler};
+use expand::expand_dotted_names;
+use filter::FieldFilter;
+use limits::{limit_fields, serialized_len, truncate_largest, truncate_strings, DROPPED_FIELDS};
+use record::JsonRecord;
+use redact::{Redaction, REDACTED};
 use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use time::format_description::well_known::Iso8601;
-use time::formatting::Formattable;
-use time::OffsetDateTime;
+use timestamp::TimestampFormat;
 use tracing::level_filters::LevelFilter;
 use tracing::span::{Attributes, Record};
 use tracing::{Event, Id, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer;
 use tracing_subscriber::layer::Context;
 #[allow(unused_imports)]
 use tracing_subscriber::prelude::*;
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
 
 #[derive(Debug)]
-struct CustomFieldStorage(Map<String, Value>);
+struct CustomFieldStorage {
+    fields: Map<String, Value>,
+    /// When the span was created, according to the monotonic reading of the clock of the layer
+    started: Duration,
+    /// Incremented whenever the span records new fields
+    version: Arc<AtomicU64>,
+    /// Incremented whenever any span in the same tree records new fields
+    tree_version: Arc<AtomicU64>,
+    /// The fields of the span combined with those of its ancestors, rebuilt when out of date
+    inherited: Option<Arc<InheritedFields>>,
+}
+
+/// What a span and its ancestors contribute to records, built from what its parent contributes
+/// when the span is created, so that writing an event doesn't need to look at every span in its
+/// scope.
+#[derive(Debug)]
+struct InheritedFields {
+    id: u64,
+    /// The fields of the span and its ancestors combined, with [`SpanFormat::Flat`]
+    fields: SpanFields,
+    /// The names and fields of the span and its ancestors, from the root, with
+    /// [`SpanFormat::Nested`]
+    spans: Value,
+    /// The names of the span and its ancestors, from the root
+    span_path: Value,
+    /// The version of the span when these were built
+    version: u64,
+    /// The current version of the span
+    current_version: Arc<AtomicU64>,
+    /// The current version of the tree of the span
+    tree_version: Arc<AtomicU64>,
+    /// The version of the tree when these were last known to be current
+    checked_tree_version: AtomicU64,
+    parent: Option<Arc<InheritedFields>>,
+    root_id: u64,
+    started: Duration,
+    root_started: Duration,
+}
+
+impl InheritedFields {
+    fn new<'a, R>(
+        span: &SpanRef<'a, R>,
+        storage: &CustomFieldStorage,
+        parent: Option<Arc<InheritedFields>>,
+        options: &Options,
+    ) -> Self
+    where
+        R: LookupSpan<'a>,
+    {
+        // Read before the version of the span, so that recording in between is noticed
+        let checked_tree_version = match &parent {
+            Some(parent) => parent.checked_tree_version.load(Ordering::Relaxed),
+            None => storage.tree_version.load(Ordering::Acquire),
+        };
+        let id = span.id().into_u64();
+
+        let mut fields = SpanFields::default();
+        let mut spans = vec![];
+        match options.span_format {
+            SpanFormat::Flat => {
+                let parent_fields = parent.as_ref().map(|parent| &parent.fields);
+                fields = SpanFields::new(
+                    options.conflict_policy,
+                    parent_fields,
+                    span.name(),
+                    &storage.fields,
+                );
+            }
+            SpanFormat::Nested => {
+                if let Some(Value::Array(parent_spans)) = parent.as_ref().map(|p| &p.spans) {
+                    spans.clone_from(parent_spans);
+                }
+                let fields = if options.expand_dotted_names {
+                    let mut fields = JsonRecord::new();
+                    for (key, value) in storage.fields.iter() {
+                        fields.insert(key.as_str(), Cow::Borrowed(value));
+                    }
+                    expand_dotted_names(fields, options.json_field_suffix.as_deref()).into_map()
+                } else {
+                    storage.fields.clone()
+                };
+                spans.push(serde_json::json!({
+                    "name": span.name(),
+                    "fields": fields,
+                }));
+            }
+        }
+        let mut span_path = match parent.as_ref().map(|parent| &parent.span_path) {
+            Some(Value::Array(parent_path)) => parent_path.clone(),
+            _ => vec![],
+        };
+        span_path.push(span.name().into());
+
+        InheritedFields {
+            id,
+            fields,
+            spans: spans.into(),
+            span_path: span_path.into(),
+            version: storage.version.load(Ordering::Acquire),
+            current_version: storage.version.clone(),
+            tree_version: storage.tree_version.clone(),
+            checked_tree_version: AtomicU64::new(checked_tree_version),
+            root_id: parent.as_ref().map_or(id, |parent| parent.root_id),
+            started: storage.started,
+            root_started: parent
+                .as_ref()
+                .map_or(storage.started, |parent| parent.root_started),
+            parent,
+        }
+    }
+
+    /// The span, with [`SpanFormat::Nested`].
+    fn span(&self) -> Option<&Value> {
+        self.spans.as_array()?.last()
+    }
+
+    /// Whether neither the span nor its ancestors have recorded new fields since these were
+    /// built.
+    fn is_current(&self) -> bool {
+        // Only look at the ancestors if some span in the tree recorded new fields since the
+        // last time
+        let tree_version = self.tree_version.load(Ordering::Acquire);
+        if self.checked_tree_version.load(Ordering::Relaxed) == tree_version {
+            return true;
+        }
+        let current = std::iter::successors(Some(self), |inherited| inherited.parent.as_deref())
+            .all(|inherited| {
+                inherited.version == inherited.current_version.load(Ordering::Acquire)
+            });
+        if current {
+            self.checked_tree_version
+                .store(tree_version, Ordering::Relaxed);
+        }
+        current
+    }
+}
 
 /// Something that can be used to write output from a [`JsonLayer`].
 ///
 /// Primarily intended to allow custom outputs in unit testing.
 pub trait JsonOutput {
-    fn write(&self, value: Value);
+    /// Writes one event. Errors are passed to the error handler of the [`JsonLayer`].
+    fn write(&self, value: Value) -> Result<(), Error>;
+
+    /// Writes one event already serialized as a single line of JSON, including the trailing
+    /// newline. Only called if [`JsonOutput::accepts_bytes`] returns `true`.
+    fn write_bytes(&self, line: &[u8]) -> Result<(), Error> {
+        self.write(serde_json::from_slice(line)?)
+    }
+
+    /// Whether events are passed to [`JsonOutput::write_bytes`] instead of
+    /// [`JsonOutput::write`], which avoids building a [`Value`] for every event.
+    fn accepts_bytes(&self) -> bool {
+        false
+    }
+
+    /// Flushes any buffered output.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Serializes `value` as a single line (or a pretty printed block) followed by a newline.
+fn json_line(value: &Value, pretty: bool) -> Result<Vec<u8>, Error> {
+    let mut buf = if pretty {
+        serde_json::to_vec_pretty(value)?
+    } else {
+        serde_json::to_vec(value)?
+    };
+    buf.push(b'\n');
+    Ok(buf)
+}
+
+/// Writes `value` using one call to `write_all`.
+fn write_json(writer: &mut impl Write, value: &Value, pretty: bool) -> Result<(), Error> {
+    // Write the whole line at once to avoid interleaving with other writers
+    writer.write_all(&json_line(value, pretty)?)?;
+    Ok(())
 }
 
 /// Default [`JsonOutput`] writing to stdout.
@@ -81,107 +343,562 @@ pub struct JsonStdout {
 }
 
 impl JsonOutput for JsonStdout {
-    fn write(&self, value: Value) {
-        println!(
-            "{}",
-            if self.pretty {
-                serde_json::to_string_pretty(&value).unwrap()
-            } else {
-                serde_json::to_string(&value).unwrap()
-            }
-        );
+    fn write(&self, value: Value) -> Result<(), Error> {
+        write_json(&mut std::io::stdout().lock(), &value, self.pretty)
+    }
+
+    fn write_bytes(&self, line: &[u8]) -> Result<(), Error> {
+        Ok(std::io::stdout().lock().write_all(line)?)
+    }
+
+    fn accepts_bytes(&self) -> bool {
+        !self.pretty
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(std::io::stdout().flush()?)
+    }
+}
+
+/// [`JsonOutput`] writing newline delimited JSON to any [`MakeWriter`].
+///
+/// This allows writing to e.g. stderr, files or a
+/// [`tracing_appender::non_blocking`](https://docs.rs/tracing-appender/latest/tracing_appender/non_blocking/index.html)
+/// writer.
+pub struct JsonWriter<W> {
+    make_writer: W,
+    pretty: bool,
+}
+
+impl<W> JsonWriter<W>
+where
+    W: for<'a> MakeWriter<'a>,
+{
+    pub fn new(make_writer: W) -> Self {
+        JsonWriter {
+            make_writer,
+            pretty: false,
+        }
+    }
+
+    pub fn pretty(make_writer: W) -> Self {
+        JsonWriter {
+            make_writer,
+            pretty: true,
+        }
+    }
+}
+
+impl<W> JsonOutput for JsonWriter<W>
+where
+    W: for<'a> MakeWriter<'a>,
+{
+    fn write(&self, value: Value) -> Result<(), Error> {
+        write_json(&mut self.make_writer.make_writer(), &value, self.pretty)
+    }
+
+    fn write_bytes(&self, line: &[u8]) -> Result<(), Error> {
+        Ok(self.make_writer.make_writer().write_all(line)?)
+    }
+
+    fn accepts_bytes(&self) -> bool {
+        !self.pretty
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(self.make_writer.make_writer().flush()?)
     }
 }
 
 /// An implementation of a [`tracing_subscriber::Layer`] that writes events as JSON using a
 /// [`JsonOutput`].
-pub struct JsonLayer<O = JsonStdout, F = Iso8601> {
+///
+/// Failures to produce output never panic. Instead they are passed to an error handler, which
+/// by default writes a diagnostic line to stderr at most once per second. See
+/// [`JsonLayer::with_error_handler`].
+pub struct JsonLayer<O = JsonStdout, F = Iso8601, C = SystemClock> {
     output: O,
     timestamp_format: F,
+    clock: C,
+    options: Options,
+}
+
+/// The configuration of a [`JsonLayer`] not depending on its type parameters.
+struct Options {
     max_level: LevelFilter,
+    on_error: ErrorHandler,
+    span_format: SpanFormat,
+    conflict_policy: ConflictPolicy,
+    reserved_field_policy: ReservedFieldPolicy,
+    field_names: FieldNames,
+    span_close_events: bool,
+    static_fields: Map<String, Value>,
+    dynamic_fields: Vec<(String, DynamicField)>,
+    field_filter: FieldFilter,
+    redaction: Redaction,
+    #[cfg(feature = "pseudonymize")]
+    pseudonymizer: Option<pseudonymize::Pseudonymizer>,
+    limits: Limits,
+    json_field_suffix: Option<String>,
+    expand_dotted_names: bool,
+}
+
+impl Options {
+    /// Applies the [`Redaction`] rules, and the pseudonymization, to the value of a field, and
+    /// to the values nested in it by their keys.
+    fn protect(&self, name: &str, value: Value) -> Value {
+        if self.redaction.redacts_field(name) {
+            return REDACTED.into();
+        }
+        #[cfg(feature = "pseudonymize")]
+        if let Some(pseudonymizer) = &self.pseudonymizer {
+            if pseudonymizer.matches(name) {
+                return pseudonymizer.pseudonymize(&value);
+            }
+        }
+        match value {
+            Value::Object(fields) => fields
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = self.protect(&key, value);
+                    (key, value)
+                })
+                .collect(),
+            Value::Array(values) => values
+                .into_iter()
+                .map(|value| self.protect(name, value))
+                .collect(),
+            value => self.redaction.mask(value),
+        }
+    }
 }
 
+type DynamicField = Box<dyn Fn() -> Value + Send + Sync>;
+
 impl Default for JsonLayer {
     fn default() -> Self {
         JsonLayer {
             output: JsonStdout::default(),
             timestamp_format: Iso8601::DEFAULT,
-            max_level: LevelFilter::INFO,
+            clock: SystemClock,
+            options: Options {
+                max_level: LevelFilter::INFO,
+                on_error: default_error_handler(),
+                span_format: SpanFormat::default(),
+                conflict_policy: ConflictPolicy::default(),
+                reserved_field_policy: ReservedFieldPolicy::default(),
+                field_names: FieldNames::default(),
+                span_close_events: false,
+                static_fields: Map::new(),
+                dynamic_fields: vec![],
+                field_filter: FieldFilter::default(),
+                redaction: Redaction::default(),
+                #[cfg(feature = "pseudonymize")]
+                pseudonymizer: None,
+                limits: Limits::default(),
+                json_field_suffix: None,
+                expand_dotted_names: false,
+            },
+        }
+    }
+}
+
+/// How the fields of spans are included in the output of a [`JsonLayer`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanFormat {
+    /// The fields of all spans are merged into the top level object of an event. Fields with the
+    /// same name are combined according to the [`ConflictPolicy`].
+    #[default]
+    Flat,
+    /// The spans are output as a `spans` array from root to leaf, and the innermost span as
+    /// `span`. Each span is an object like `{"name": "A span", "fields": {"span_field": 42}}`.
+    Nested,
+}
+
+/// The names of the fields added to every event by a [`JsonLayer`].
+///
+/// Setting a name to `None` leaves the field out of the output.
+///
+/// ```
+/// use tracing_json_span_fields::{FieldNames, JsonLayer};
+/// let layer = JsonLayer::default().with_field_names(FieldNames {
+///     log_level: Some("level".to_string()),
+///     timestamp: Some("@timestamp".to_string()),
+///     target: Some("logger".to_string()),
+///     message: Some("msg".to_string()),
+///     name: None,
+///     ..FieldNames::default()
+/// });
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldNames {
+    /// The target of the event, default `target`
+    pub target: Option<String>,
+    /// The name of the event, including its source location, default `name`
+    pub name: Option<String>,
+    /// The level of the event, default `log_level`
+    pub log_level: Option<String>,
+    /// The time of the event, default `timestamp`
+    pub timestamp: Option<String>,
+    /// The message of the event, default `message`
+    pub message: Option<String>,
+    /// The source file of the event, default none
+    pub file: Option<String>,
+    /// The line number in the source file of the event, default none
+    pub line: Option<String>,
+    /// The module of the event, default none
+    pub module_path: Option<String>,
+    /// The name of the thread of the event, if it has a name, default none
+    pub thread_name: Option<String>,
+    /// The id of the thread of the event, default none
+    pub thread_id: Option<String>,
+    /// The [`Id`] of the innermost span of the event, default none
+    pub span_id: Option<String>,
+    /// The [`Id`] of the parent of the innermost span of the event, default none
+    pub parent_span_id: Option<String>,
+    /// The [`Id`] of the root span of the event, default none
+    pub root_span_id: Option<String>,
+    /// The names of the spans of the event from root to leaf, default none
+    pub span_path: Option<String>,
+    /// The milliseconds since the innermost span of the event was created, default none
+    pub elapsed_ms: Option<String>,
+    /// The milliseconds since the root span of the event was created, default none
+    pub root_elapsed_ms: Option<String>,
+}
+
+impl Default for FieldNames {
+    fn default() -> Self {
+        FieldNames {
+            target: Some("target".to_string()),
+            name: Some("name".to_string()),
+            log_level: Some("log_level".to_string()),
+            timestamp: Some("timestamp".to_string()),
+            message: Some("message".to_string()),
+            file: None,
+            line: None,
+            module_path: None,
+            thread_name: None,
+            thread_id: None,
+            span_id: None,
+            parent_span_id: None,
+            root_span_id: None,
+            span_path: None,
+            elapsed_ms: None,
+            root_elapsed_ms: None,
         }
     }
 }
 
+impl FieldNames {
+    /// The names of the fields added by the layer, which would overwrite fields of spans and
+    /// events with the same name.
+    fn reserved(&self) -> Vec<&str> {
+        [
+            &self.target,
+            &self.name,
+            &self.log_level,
+            &self.timestamp,
+            &self.file,
+            &self.line,
+            &self.module_path,
+            &self.thread_name,
+            &self.thread_id,
+            &self.span_id,
+            &self.parent_span_id,
+            &self.root_span_id,
+            &self.span_path,
+            &self.elapsed_ms,
+            &self.root_elapsed_ms,
+        ]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect()
+    }
+}
+
+/// Names an optional field `default` when enabling it, unless it already has a name, and
+/// removes it when disabling it, unless it has been given another name.
+fn set_default_name(name: &mut Option<String>, enabled: bool, default: &str) {
+    match (enabled, name.as_deref()) {
+        (true, None) => *name = Some(default.to_string()),
+        (false, Some(current)) if current == default => *name = None,
+        _ => {}
+    }
+}
+
 impl JsonLayer<JsonStdout, Iso8601> {
     pub fn pretty() -> JsonLayer<JsonStdout, Iso8601> {
         JsonLayer::default().with_output(JsonStdout { pretty: true })
     }
 }
 
-impl<O, F> JsonLayer<O, F>
+impl<O, F, C> JsonLayer<O, F, C>
 where
-    F: Formattable,
+    F: TimestampFormat,
     O: JsonOutput,
+    C: Clock,
 {
-    pub fn with_output<O2>(self, output: O2) -> JsonLayer<O2, F>
+    pub fn with_output<O2>(self, output: O2) -> JsonLayer<O2, F, C>
     where
         O2: JsonOutput,
     {
         JsonLayer {
             output,
             timestamp_format: self.timestamp_format,
-            max_level: self.max_level,
+            clock: self.clock,
+            options: self.options,
         }
     }
 
-    pub fn with_timestamp_format<F2>(self, timestamp_format: F2) -> JsonLayer<O, F2>
+    pub fn with_timestamp_format<F2>(self, timestamp_format: F2) -> JsonLayer<O, F2, C>
     where
-        F2: Formattable,
+        F2: TimestampFormat,
     {
         JsonLayer {
             output: self.output,
             timestamp_format,
-            max_level: self.max_level,
+            clock: self.clock,
+            options: self.options,
         }
     }
 
-    pub fn with_level(self, max_level: LevelFilter) -> JsonLayer<O, F> {
+    /// Sets the [`Clock`] used for timestamps. Its monotonic [`Clock::instant`] measures the
+    /// times written with [`with_elapsed_ms`](Self::with_elapsed_ms) and
+    /// [`with_span_close_events`](Self::with_span_close_events).
+    pub fn with_clock<C2>(self, clock: C2) -> JsonLayer<O, F, C2>
+    where
+        C2: Clock,
+    {
         JsonLayer {
             output: self.output,
             timestamp_format: self.timestamp_format,
-            max_level,
+            clock,
+            options: self.options,
         }
     }
+
+    pub fn with_level(mut self, max_level: LevelFilter) -> JsonLayer<O, F, C> {
+        self.options.max_level = max_level;
+        self
+    }
+
+    /// Sets the function called when an event cannot be written, replacing the default of
+    /// reporting the error on stderr.
+    pub fn with_error_handler<H>(mut self, on_error: H) -> JsonLayer<O, F, C>
+    where
+        H: Fn(Error) + Send + Sync + 'static,
+    {
+        self.options.on_error = Box::new(on_error);
+        self
+    }
+
+    pub fn with_span_format(mut self, span_format: SpanFormat) -> JsonLayer<O, F, C> {
+        self.options.span_format = span_format;
+        self
+    }
+
+    /// Sets the names of the fields added by the layer, replacing all names set before,
+    /// including those filled in by methods like
+    /// [`with_source_location`](Self::with_source_location).
+    pub fn with_field_names(mut self, field_names: FieldNames) -> JsonLayer<O, F, C> {
+        self.options.field_names = field_names;
+        self
+    }
+
+    /// Adds the `file`, `line` and `module_path` of events to the output.
+    ///
+    /// Like the other methods adding fields, this only fills in the default names of fields
+    /// without a name set by [`with_field_names`](Self::with_field_names), and disabling only
+    /// removes fields with the default names.
+    pub fn with_source_location(mut self, enabled: bool) -> JsonLayer<O, F, C> {
+        let names = &mut self.options.field_names;
+        set_default_name(&mut names.file, enabled, "file");
+        set_default_name(&mut names.line, enabled, "line");
+        set_default_name(&mut names.module_path, enabled, "module_path");
+        self
+    }
+
+    /// Adds the `thread_name` and `thread_id` of events to the output.
+    pub fn with_thread_info(mut self, enabled: bool) -> JsonLayer<O, F, C> {
+        let names = &mut self.options.field_names;
+        set_default_name(&mut names.thread_name, enabled, "thread_name");
+        set_default_name(&mut names.thread_id, enabled, "thread_id");
+        self
+    }
+
+    /// Adds the `span_id`, `parent_span_id`, `root_span_id` and `span_path` of events to the
+    /// output.
+    pub fn with_span_ids(mut self, enabled: bool) -> JsonLayer<O, F, C> {
+        let names = &mut self.options.field_names;
+        set_default_name(&mut names.span_id, enabled, "span_id");
+        set_default_name(&mut names.parent_span_id, enabled, "parent_span_id");
+        set_default_name(&mut names.root_span_id, enabled, "root_span_id");
+        set_default_name(&mut names.span_path, enabled, "span_path");
+        self
+    }
+
+    /// Adds the milliseconds since the innermost span of events was created as `elapsed_ms`,
+    /// and since the root span was created as `root_elapsed_ms`.
+    pub fn with_elapsed_ms(mut self, enabled: bool) -> JsonLayer<O, F, C> {
+        let names = &mut self.options.field_names;
+        set_default_name(&mut names.elapsed_ms, enabled, "elapsed_ms");
+        set_default_name(&mut names.root_elapsed_ms, enabled, "root_elapsed_ms");
+        self
+    }
+
+    /// Adds fields with constant values, such as the name and version of the service, to every
+    /// record.
+    ///
+    /// The fields are treated as if they belonged to a span outside of all other spans.
+    pub fn with_static_fields(mut self, fields: Map<String, Value>) -> JsonLayer<O, F, C> {
+        self.options.static_fields.extend(fields);
+        self
+    }
+
+    /// Adds a field to every record with the value returned by `value` at the time of writing
+    /// the record.
+    ///
+    /// Like the static fields, the field is treated as if it belonged to a span outside of all
+    /// other spans.
+    pub fn with_dynamic_field<V>(mut self, name: impl Into<String>, value: V) -> JsonLayer<O, F, C>
+    where
+        V: Fn() -> Value + Send + Sync + 'static,
+    {
+        self.options
+            .dynamic_fields
+            .push((name.into(), Box::new(value)));
+        self
+    }
+
+    /// Writes a record when a span closes, with the fields of the span and its parents, and
+    /// the time in nanoseconds between creating and closing the span as `duration_ns`, split
+    /// into time spent inside the span as `busy_ns` and outside of it as `idle_ns`.
+    pub fn with_span_close_events(mut self, enabled: bool) -> JsonLayer<O, F, C> {
+        self.options.span_close_events = enabled;
+        self
+    }
+
+    /// Sets the rules for dropping fields of spans and events depending on their target.
+    pub fn with_field_filter(mut self, field_filter: FieldFilter) -> JsonLayer<O, F, C> {
+        self.options.field_filter = field_filter;
+        self
+    }
+
+    /// Sets the rules for removing secrets from the fields of spans and events.
+    pub fn with_redaction(mut self, redaction: Redaction) -> JsonLayer<O, F, C> {
+        self.options.redaction = redaction;
+        self
+    }
+
+    /// Sets the fields, and the values nested in objects by key, replaced by keyed hashes.
+    /// Fields redacted by [`JsonLayer::with_redaction`] are not pseudonymized.
+    #[cfg(feature = "pseudonymize")]
+    pub fn with_pseudonymizer(
+        mut self,
+        pseudonymizer: pseudonymize::Pseudonymizer,
+    ) -> JsonLayer<O, F, C> {
+        self.options.pseudonymizer = Some(pseudonymizer);
+        self
+    }
+
+    /// Sets limits on the size of records.
+    pub fn with_limits(mut self, limits: Limits) -> JsonLayer<O, F, C> {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Parses string values of fields with a name ending with `suffix`, such as `.json`, and
+    /// writes them as nested JSON. Values that aren't valid JSON are written as strings.
+    ///
+    /// With [`with_expanded_dotted_names`](Self::with_expanded_dotted_names), the suffix is
+    /// removed from the names, so that `headers.json` is written as `headers`.
+    pub fn with_json_field_suffix(mut self, suffix: impl Into<String>) -> JsonLayer<O, F, C> {
+        self.options.json_field_suffix = Some(suffix.into());
+        self
+    }
+
+    /// Expands fields of spans and events with dotted names, such as `http.method`, into nested
+    /// objects, such as `{"http": {"method": "GET"}}`.
+    ///
+    /// When a name is both a field and a prefix of other fields, like `http` and `http.method`,
+    /// the value of the field is written as `_value` in the object. Names with empty parts,
+    /// such as `a..b`, are kept as they are. Dotted names are expanded after the fields are
+    /// filtered, redacted and combined according to the [`ConflictPolicy`]. The suffix set with
+    /// [`with_json_field_suffix`](Self::with_json_field_suffix) is removed from names first,
+    /// so it doesn't become a nested object of its own.
+    pub fn with_expanded_dotted_names(mut self, enabled: bool) -> JsonLayer<O, F, C> {
+        self.options.expand_dotted_names = enabled;
+        self
+    }
+
+    pub fn with_conflict_policy(mut self, conflict_policy: ConflictPolicy) -> JsonLayer<O, F, C> {
+        self.options.conflict_policy = conflict_policy;
+        self
+    }
+
+    pub fn with_reserved_field_policy(
+        mut self,
+        reserved_field_policy: ReservedFieldPolicy,
+    ) -> JsonLayer<O, F, C> {
+        self.options.reserved_field_policy = reserved_field_policy;
+        self
+    }
 }
 
-impl<S, O, F> layer::Layer<S> for JsonLayer<O, F>
+impl<S, O, F, C> layer::Layer<S> for JsonLayer<O, F, C>
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
     O: JsonOutput + 'static,
-    F: Formattable + 'static,
+    F: TimestampFormat + 'static,
+    C: Clock + 'static,
 {
     fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
-        metadata.level() <= &self.max_level
+        metadata.level() <= &self.options.max_level
     }
 
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
         // Build our json object from the field values like we have been
         let mut fields = Map::new();
-        let mut visitor = JsonVisitor(&mut fields);
+        let mut visitor = JsonVisitor::new(&mut fields, &self.options, attrs.metadata(), true);
         attrs.record(&mut visitor);
 
-        // And stuff it in our newtype.
-        let storage = CustomFieldStorage(fields);
+        let started = self.clock.instant();
 
         // Get a reference to the internal span data
         let span = ctx.span(id).unwrap();
+
+        // Combine the fields with those of the parent once, instead of for every event
+        let parent = span.parent().map(|parent| self.inherited_fields(&parent));
+        let tree_version = match &parent {
+            Some(parent) => parent.tree_version.clone(),
+            None => Arc::new(AtomicU64::new(0)),
+        };
+        // And stuff it in our newtype.
+        let mut storage = CustomFieldStorage {
+            fields,
+            started,
+            version: Arc::new(AtomicU64::new(0)),
+            tree_version,
+            inherited: None,
+        };
+        storage.inherited = Some(Arc::new(InheritedFields::new(
+            &span,
+            &storage,
+            parent,
+            &self.options,
+        )));
         // Get the special place where tracing stores custom data
         let mut extensions = span.extensions_mut();
         // And store our data
         extensions.insert::<CustomFieldStorage>(storage);
+        if self.options.span_close_events {
+            extensions.insert(Timings::new(started));
+        }
     }
 
     fn max_level_hint(&self) -> Option<LevelFilter> {
-        Some(self.max_level)
+        Some(self.options.max_level)
     }
 
     fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
@@ -192,99 +909,452 @@ where
         let mut extensions_mut = span.extensions_mut();
         let custom_field_storage: &mut CustomFieldStorage =
             extensions_mut.get_mut::<CustomFieldStorage>().unwrap();
-        let json_data: &mut Map<String, Value> = &mut custom_field_storage.0;
+        let json_data: &mut Map<String, Value> = &mut custom_field_storage.fields;
 
         // And add to using our old friend the visitor!
-        let mut visitor = JsonVisitor(json_data);
+        let mut visitor = JsonVisitor::new(json_data, &self.options, span.metadata(), true);
         values.record(&mut visitor);
+
+        // The inherited fields of the span and its descendants are rebuilt when next needed
+        custom_field_storage.version.fetch_add(1, Ordering::Release);
+        custom_field_storage
+            .tree_version
+            .fetch_add(1, Ordering::Release);
     }
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
         let mut fields = Map::new();
+        let mut visitor = JsonVisitor::new(&mut fields, &self.options, event.metadata(), false);
+        event.record(&mut visitor);
 
-        // The fields of the spans
-        if let Some(scope) = ctx.event_scope(event) {
-            for span in scope.from_root() {
-                let extensions = span.extensions();
-                let storage = extensions.get::<CustomFieldStorage>().unwrap();
-                let field_data: &Map<String, Value> = &storage.0;
+        let inherited = ctx
+            .event_span(event)
+            .map(|span| self.inherited_fields(&span));
+        self.write_record(event.metadata(), inherited.as_deref(), fields);
+    }
 
-                for (key, value) in field_data {
-                    fields.insert(key.clone(), value.clone());
-                }
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if !self.options.span_close_events {
+            return;
+        }
+        let span = ctx.span(id).unwrap();
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            let now = self.clock.instant();
+            timings.idle += since(timings.last, now);
+            timings.last = now;
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if !self.options.span_close_events {
+            return;
+        }
+        let span = ctx.span(id).unwrap();
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            let now = self.clock.instant();
+            timings.busy += since(timings.last, now);
+            timings.last = now;
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if !self.options.span_close_events {
+            return;
+        }
+        let span = ctx.span(&id).unwrap();
+        let mut extensions = span.extensions_mut();
+        if let Some(mut timings) = extensions.remove::<Timings>() {
+            let started = extensions.get_mut::<CustomFieldStorage>().unwrap().started;
+            drop(extensions);
+            let now = self.clock.instant();
+            timings.idle += since(timings.last, now);
+
+            let mut fields = Map::new();
+            fields.insert("message".to_string(), "close".into());
+            fields.insert("duration_ns".to_string(), nanos(since(started, now)));
+            fields.insert("busy_ns".to_string(), nanos(timings.busy));
+            fields.insert("idle_ns".to_string(), nanos(timings.idle));
+
+            let inherited = self.inherited_fields(&span);
+            self.write_record(span.metadata(), Some(&inherited), fields);
+        }
+    }
+}
+
+impl<O, F, C> JsonLayer<O, F, C>
+where
+    O: JsonOutput,
+    F: TimestampFormat,
+    C: Clock,
+{
+    /// The [`InheritedFields`] of `span`, rebuilt if they are out of date.
+    fn inherited_fields<'a, R>(&self, span: &SpanRef<'a, R>) -> Arc<InheritedFields>
+    where
+        R: LookupSpan<'a>,
+    {
+        if let Some(inherited) = &span
+            .extensions()
+            .get::<CustomFieldStorage>()
+            .unwrap()
+            .inherited
+        {
+            if inherited.is_current() {
+                return inherited.clone();
             }
         }
 
+        // Rebuilt on top of the current fields of the parent
+        let parent = span.parent().map(|parent| self.inherited_fields(&parent));
+        let mut extensions = span.extensions_mut();
+        let storage = extensions.get_mut::<CustomFieldStorage>().unwrap();
+        let inherited = Arc::new(InheritedFields::new(span, storage, parent, &self.options));
+        storage.inherited = Some(inherited.clone());
+        inherited
+    }
+
+    /// Writes an event, or a span closing, with the fields of its spans and the fields added by
+    /// the layer.
+    fn write_record<'s>(
+        &'s self,
+        metadata: &Metadata<'_>,
+        inherited: Option<&InheritedFields>,
+        own_fields: Map<String, Value>,
+    ) {
+        let mut merger = FieldMerger::new(self.options.conflict_policy);
+        for (key, value) in &self.options.static_fields {
+            merger.add_global(key, Cow::Borrowed(value));
+        }
+        for (key, value) in &self.options.dynamic_fields {
+            merger.add_global(key, Cow::Owned(value()));
+        }
+
+        // The fields of the spans, combined when the span was created
+        if let (Some(inherited), SpanFormat::Flat) = (inherited, self.options.span_format) {
+            merger.add_spans(&inherited.fields);
+        }
+
         // The fields of the event
-        let mut visitor = JsonVisitor(&mut fields);
-        event.record(&mut visitor);
+        merger.add_event(&own_fields);
+        let mut fields = merger.finish();
+
+        let limits = &self.options.limits;
+        if let Some(max_fields) = limits.max_fields {
+            let dropped = limit_fields(&mut fields, max_fields, |key| own_fields.contains_key(key));
+            if dropped > 0 {
+                fields.insert(DROPPED_FIELDS, Cow::Owned(dropped.into()));
+            }
+        }
 
-        // Add default fields
-        fields.insert("target".to_string(), event.metadata().target().into());
-        fields.insert("name".to_string(), event.metadata().name().into());
-        fields.insert(
-            "log_level".to_string(),
-            event.metadata().level().as_str().into(),
-        );
-        fields.insert(
-            "timestamp".to_string(),
-            OffsetDateTime::now_utc()
-                .format(&self.timestamp_format)
-                .unwrap()
-                .into(),
-        );
+        let names = &self.options.field_names;
+        let message = fields.remove("message");
 
-        // And create our output
-        let output = fields.into();
+        if self.options.expand_dotted_names {
+            fields = expand_dotted_names(fields, self.options.json_field_suffix.as_deref());
+        }
 
-        self.output.write(output);
-    }
-}
+        if self.options.reserved_field_policy == ReservedFieldPolicy::Nest {
+            let mut reserved = names.reserved();
+            // The message was taken out above, but a renamed message key can still collide
+            reserved.extend(names.message.as_deref());
+            if self.options.span_format == SpanFormat::Nested {
+                reserved.extend(["span", "spans"]);
+            }
+            nest_reserved_fields(&mut fields, &reserved);
+        }
 
-struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+        // Add default fields first, so they also come first with the `preserve_order` feature
+        let mut record = JsonRecord::new();
+        let mut insert = |key: &'s str, value: Value| record.insert(key, Cow::Owned(value));
+        if let Some(key) = &names.timestamp {
+            match self.timestamp_format.format_timestamp(self.clock.now()) {
+                Ok(timestamp) => {
+                    insert(key, timestamp);
+                }
+                // Still output the event, just without a timestamp
+                Err(e) => (self.options.on_error)(e),
+            }
+        }
+        if let Some(key) = &names.log_level {
+            insert(key, metadata.level().as_str().into());
+        }
+        if let (Some(key), Some(message)) = (&names.message, message) {
+            insert(key, message.into_owned());
+        }
+        if let Some(key) = &names.target {
+            insert(key, metadata.target().into());
+        }
+        if let Some(key) = &names.name {
+            insert(key, metadata.name().into());
+        }
+        if let (Some(key), Some(file)) = (&names.file, metadata.file()) {
+            insert(key, file.into());
+        }
+        if let (Some(key), Some(line)) = (&names.line, metadata.line()) {
+            insert(key, line.into());
+        }
+        if let (Some(key), Some(module_path)) = (&names.module_path, metadata.module_path()) {
+            insert(key, module_path.into());
+        }
+        if names.thread_name.is_some() || names.thread_id.is_some() {
+            let thread = std::thread::current();
+            if let (Some(key), Some(thread_name)) = (&names.thread_name, thread.name()) {
+                insert(key, thread_name.into());
+            }
+            if let Some(key) = &names.thread_id {
+                insert(key, thread_id_value(thread.id()));
+            }
+        }
+        if let Some(inherited) = inherited {
+            if let Some(key) = &names.span_id {
+                insert(key, inherited.id.into());
+            }
+            if let (Some(key), Some(parent)) = (&names.parent_span_id, &inherited.parent) {
+                insert(key, parent.id.into());
+            }
+            if let Some(key) = &names.root_span_id {
+                insert(key, inherited.root_id.into());
+            }
+            if let Some(key) = &names.span_path {
+                record.insert(key, Cow::Borrowed(&inherited.span_path));
+            }
+            if names.elapsed_ms.is_some() || names.root_elapsed_ms.is_some() {
+                let now = self.clock.instant();
+                if let Some(key) = &names.elapsed_ms {
+                    let elapsed = millis(since(inherited.started, now));
+                    record.insert(key, Cow::Owned(elapsed));
+                }
+                if let Some(key) = &names.root_elapsed_ms {
+                    let elapsed = millis(since(inherited.root_started, now));
+                    record.insert(key, Cow::Owned(elapsed));
+                }
+            }
+            if let Some(span) = inherited.span() {
+                record.insert("span", Cow::Borrowed(span));
+                record.insert("spans", Cow::Borrowed(&inherited.spans));
+            }
+        }
 
-impl<'a> tracing::field::Visit for JsonVisitor<'a> {
-    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
-        self.0
-            .insert(field.name().to_string(), serde_json::json!(value));
-    }
+        // Then the fields of the spans and the event, unless replaced by a default field
+        for (key, value) in fields {
+            if !record.contains_key(&key) {
+                record.insert(key, value);
+            }
+        }
 
-    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
-        self.0
-            .insert(field.name().to_string(), serde_json::json!(value));
-    }
+        // And create our output
+        let result = if self.output.accepts_bytes() {
+            let mut buffer = BUFFER.try_with(Cell::take).unwrap_or_default();
+            buffer.clear();
+            let serialized = self.serialize_record(record, &mut buffer);
+            let result = serialized.and_then(|()| self.output.write_bytes(&buffer));
+            if buffer.capacity() <= MAX_BUFFER_CAPACITY {
+                let _ = BUFFER.try_with(|cell| cell.set(buffer));
+            }
+            result
+        } else {
+            let mut record = record.into_map();
+            if let Some(max_size) = limits.max_record_size {
+                let size = serialized_len(&record);
+                if size > max_size {
+                    self.limit_record_size(&mut record, size, max_size);
+                }
+            }
+            self.output.write(record.into())
+        };
 
-    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
-        self.0
-            .insert(field.name().to_string(), serde_json::json!(value));
+        if let Err(e) = result {
+            (self.options.on_error)(e);
+        }
     }
 
-    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
-        self.0
-            .insert(field.name().to_string(), serde_json::json!(value));
+    /// Serializes `record` as a line of JSON into `buffer`, applying the maximum record size.
+    fn serialize_record(&self, record: JsonRecord<'_>, buffer: &mut Vec<u8>) -> Result<(), Error> {
+        serde_json::to_writer(&mut *buffer, &record)?;
+        if let Some(max_size) = self.options.limits.max_record_size {
+            let size = buffer.len();
+            if size > max_size {
+                let mut record = record.into_map();
+                self.limit_record_size(&mut record, size, max_size);
+                buffer.clear();
+                serde_json::to_writer(&mut *buffer, &record)?;
+            }
+        }
+        buffer.push(b'\n');
+        Ok(())
     }
 
-    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
-        self.0
-            .insert(field.name().to_string(), serde_json::json!(value));
+    /// Applies the [`OversizePolicy`] to a record of `size` bytes.
+    fn limit_record_size(&self, record: &mut Map<String, Value>, size: usize, max_size: usize) {
+        let names = &self.options.field_names;
+        let mut kept = names.reserved();
+        kept.push(DROPPED_FIELDS);
+        let fits = match self.options.limits.oversize_policy {
+            OversizePolicy::TruncateLargest => truncate_largest(record, &kept, max_size),
+            OversizePolicy::DropWithWarning => false,
+        };
+        // The fields added by the layer alone can be too large to fit by truncating the others
+        if fits {
+            return;
+        }
+        record.retain(|key, _| kept.contains(&key.as_str()));
+        if let Some(key) = &names.log_level {
+            record.insert(key.clone(), tracing::Level::WARN.as_str().into());
+        }
+        if let Some(key) = &names.message {
+            let message = format!(
+                "record of {} bytes exceeds the limit of {} bytes",
+                size, max_size
+            );
+            record.insert(key.clone(), message.into());
+        }
+        record.insert("record_size".to_string(), size.into());
     }
+}
 
-    fn record_error(
-        &mut self,
-        field: &tracing::field::Field,
-        value: &(dyn std::error::Error + 'static),
-    ) {
-        self.0.insert(
-            field.name().to_string(),
-            serde_json::json!(value.to_string()),
-        );
-    }
+thread_local! {
+    /// The buffer records are serialized into, reused between records written on a thread.
+    static BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// The largest buffer kept for the next record, to not hold on to the memory used by a single
+/// huge record.
+const MAX_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Time spent in and out of a span, used when writing span close events.
+struct Timings {
+    /// When the span was last entered or exited, or created
+    last: Duration,
+    busy: Duration,
+    idle: Duration,
+}
+
+impl Timings {
+    fn new(created: Duration) -> Self {
+        Timings {
+            last: created,
+            busy: Duration::ZERO,
+            idle: Duration::ZERO,
+        }
+    }
+}
+
+/// The time from `earlier` to `now`, two monotonic readings of a [`Clock`], or zero if a
+/// custom clock went backwards.
+fn since(earlier: Duration, now: Duration) -> Duration {
+    now.saturating_sub(earlier)
+}
+
+fn millis(duration: Duration) -> Value {
+    (duration.as_secs_f64() * 1000.0).into()
+}
+
+fn nanos(duration: Duration) -> Value {
+    u64::try_from(duration.as_nanos())
+        .unwrap_or(u64::MAX)
+        .into()
+}
+
+/// The numeric id of a thread, or the debug representation if that cannot be parsed.
+fn thread_id_value(id: std::thread::ThreadId) -> Value {
+    // ThreadId::as_u64 is not stable, so parse the number from "ThreadId(1)"
+    let debug = format!("{:?}", id);
+    debug
+        .trim_start_matches("ThreadId(")
+        .trim_end_matches(')')
+        .parse::<u64>()
+        .map(Value::from)
+        .unwrap_or_else(|_| debug.into())
+}
+
+/// Records the fields of a span or an event, applying the [`FieldFilter`], the parsing of
+/// [`AsJson`] and JSON fields, the [`Redaction`] rules, the pseudonymization and the maximum
+/// string length.
+struct JsonVisitor<'a> {
+    fields: &'a mut Map<String, Value>,
+    options: &'a Options,
+    target: &'a str,
+    /// Whether the fields are of a span rather than an event
+    span: bool,
+}
+
+impl<'a> JsonVisitor<'a> {
+    fn new(
+        fields: &'a mut Map<String, Value>,
+        options: &'a Options,
+        metadata: &'a Metadata<'_>,
+        span: bool,
+    ) -> Self {
+        JsonVisitor {
+            fields,
+            options,
+            target: metadata.target(),
+            span,
+        }
+    }
+
+    fn insert(&mut self, field: &tracing::field::Field, value: Value) {
+        if !self
+            .options
+            .field_filter
+            .keeps(self.target, self.span, field.name())
+        {
+            return;
+        }
+        let value = match &self.options.json_field_suffix {
+            Some(suffix) if field.name().ends_with(suffix.as_str()) => as_json::parse_json(value),
+            _ => value,
+        };
+        let mut value = self.options.protect(field.name(), value);
+        if let Some(max_len) = self.options.limits.max_string_len {
+            truncate_strings(&mut value, max_len);
+        }
+        self.fields.insert(field.name().to_string(), value);
+    }
+}
+
+impl<'a> tracing::field::Visit for JsonVisitor<'a> {
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        self.insert(field, serde_json::json!(value));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.insert(field, serde_json::json!(value));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.insert(field, serde_json::json!(value));
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.insert(field, serde_json::json!(value));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.insert(field, serde_json::json!(value));
+    }
+
+    fn record_error(
+        &mut self,
+        field: &tracing::field::Field,
+        value: &(dyn std::error::Error + 'static),
+    ) {
+        self.insert(field, serde_json::json!(value.to_string()));
+    }
 
     fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
-        self.0.insert(
-            field.name().to_string(),
-            serde_json::json!(format!("{:?}", value)),
-        );
+        // The message is always a string, even when formatted from an `AsJson`
+        if field.name() == "message" {
+            self.insert(field, serde_json::json!(format!("{:?}", value)));
+        } else {
+            self.insert(field, as_json::debug_value(value));
+        }
+    }
+
+    #[cfg(all(tracing_unstable, feature = "valuable"))]
+    fn record_value(&mut self, field: &tracing::field::Field, value: valuable::Value<'_>) {
+        self.insert(field, structured::to_json(value));
     }
 }
 
@@ -293,9 +1363,9 @@ mod tests {
     use super::*;
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
-    use time::macros::format_description;
+    use time::macros::{datetime, format_description};
     use time::parsing::Parsable;
-    use time::PrimitiveDateTime;
+    use time::{OffsetDateTime, PrimitiveDateTime};
     use tracing::field;
     use tracing::subscriber::with_default;
     use tracing_subscriber::Registry;
@@ -364,34 +1434,1043 @@ mod tests {
         data: Arc<Mutex<Vec<Value>>>,
     }
 
+    #[derive(Clone, Default)]
+    struct BufferWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for BufferWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for BufferWriter {
+        type Writer = BufferWriter;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
     impl JsonOutput for TestOutput {
-        fn write(&self, value: Value) {
+        fn write(&self, value: Value) -> Result<(), Error> {
             let mut data = self.data.lock().unwrap();
             (*data).push(value);
+            Ok(())
+        }
+    }
+
+    struct FailingOutput;
+
+    impl JsonOutput for FailingOutput {
+        fn write(&self, _value: Value) -> Result<(), Error> {
+            Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into())
         }
     }
 
     #[test]
-    fn one_span_some_fields() {
-        tracing_subscriber::fmt().pretty().init();
+    fn one_span_some_fields() {
+        tracing_subscriber::fmt().pretty().init();
+
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        tracing::info!("BEFORE");
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("Top level", field_top = 0).entered();
+            tracing::info!(field_event = "from event", "FOOBAR");
+            tracing::error!("BAZ");
+        });
+
+        tracing::info!("AFTER");
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "field_top": 0,
+                "field_event": "from event"
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "ERROR",
+                "message": "BAZ",
+                "field_top": 0,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn two_spans_different_fields() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("Top level", field_top = 0).entered();
+            let _span2 = tracing::info_span!("Second level", field_second = 1).entered();
+            tracing::info!(field_event = "from event", "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "field_top": 0,
+                "field_second": 1,
+                "field_event": "from event"
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn two_spans_same_fields() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("Top level", field_overwrite = 0).entered();
+            let _span2 = tracing::info_span!("Second level", field_overwrite = 1).entered();
+            tracing::info!(field_event = "from event", "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "field_overwrite": 1,
+                "field_event": "from event"
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn two_spans_same_fields_including_event() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("Top level", field_overwrite = 0).entered();
+            let _span2 = tracing::info_span!("Second level", field_overwrite = 1).entered();
+            tracing::info!(field_overwrite = "from event", "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "field_overwrite": "from event"
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn two_events_from_two_spans() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("Top level", field_top = 0).entered();
+            tracing::info!(field_event = "from event one", "ONE");
+            let _span2 = tracing::info_span!("Second level", field_second = 1).entered();
+            tracing::info!(field_event = "from event two", "TWO");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "ONE",
+                "field_top": 0,
+                "field_event": "from event one"
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "TWO",
+                "field_top": 0,
+                "field_second": 1,
+                "field_event": "from event two"
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn one_span_recorded_field() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let span = tracing::info_span!("A span", span_field = 0, recorded_field = field::Empty);
+            span.record("recorded_field", "foo");
+            let _span = span.entered();
+            tracing::info!(event_field = 1.1, "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "event_field": 1.1,
+                "recorded_field": "foo",
+                "span_field": 0,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn one_span_recorded_field_overwriting_initial_field() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let span = tracing::info_span!("A span", span_field = 0);
+            span.record("span_field", "foo");
+            let _span = span.entered();
+            tracing::info!(event_field = 1.1, "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "event_field": 1.1,
+                "span_field": "foo",
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn outer_span_recorded_field_after_inner_span() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let outer = tracing::info_span!("Outer span", status = field::Empty);
+            let _outer = outer.enter();
+            let _inner = tracing::info_span!("Inner span", inner_field = 1).entered();
+            tracing::info!("before");
+            outer.record("status", "done");
+            tracing::info!("after");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "before",
+                "inner_field": 1,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "after",
+                "inner_field": 1,
+                "status": "done",
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    /// The cached [`InheritedFields`] of a span of the default subscriber.
+    fn cached_inherited_fields(span: &tracing::Span) -> Arc<InheritedFields> {
+        tracing::dispatcher::get_default(|dispatch| {
+            let registry = dispatch.downcast_ref::<Registry>().unwrap();
+            let span = registry.span(&span.id().unwrap()).unwrap();
+            let extensions = span.extensions();
+            let storage = extensions.get::<CustomFieldStorage>().unwrap();
+            storage.inherited.clone().unwrap()
+        })
+    }
+
+    #[test]
+    fn recording_rebuilds_only_descendants() {
+        let layer = JsonLayer::default().with_output(TestOutput {
+            data: Arc::new(Mutex::new(vec![])),
+        });
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            let outer = tracing::info_span!("Outer span", status = field::Empty);
+            let inner = tracing::info_span!(parent: &outer, "Inner span");
+            let sibling = tracing::info_span!(parent: &outer, "Sibling span", n = field::Empty);
+            let other = tracing::info_span!("Other span");
+            outer.record("status", "done");
+
+            assert!(!cached_inherited_fields(&outer).is_current());
+            assert!(!cached_inherited_fields(&inner).is_current());
+            assert!(
+                cached_inherited_fields(&other).is_current(),
+                "an unrelated span is not rebuilt"
+            );
+
+            inner.in_scope(|| tracing::info!("rebuilt"));
+            let rebuilt = cached_inherited_fields(&inner);
+            assert!(rebuilt.is_current());
+            assert!(
+                Arc::ptr_eq(
+                    rebuilt.parent.as_ref().unwrap(),
+                    &cached_inherited_fields(&outer)
+                ),
+                "the fields of the parent are shared"
+            );
+
+            sibling.record("n", 1);
+            assert!(
+                cached_inherited_fields(&inner).is_current(),
+                "recording in a sibling doesn't rebuild"
+            );
+        });
+    }
+
+    #[test]
+    fn without_any_spans() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            tracing::info!(event_field = 1.1, "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "event_field": 1.1,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn custom_timestamp() {
+        let timestamp_format = format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]"
+        );
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_timestamp_format(timestamp_format);
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            tracing::info!(event_field = 1.1, "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name_with_format(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "event_field": 1.1,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+            &timestamp_format,
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn conflicting_fields_are_prefixed() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_conflict_policy(ConflictPolicy::Prefix);
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span1 = tracing::info_span!("Top level", field_overwrite = 0).entered();
+            let _span2 = tracing::info_span!("Second level", field_overwrite = 1).entered();
+            tracing::info!(field_overwrite = "from event", "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "field_overwrite": "from event",
+                "span.Top level.field_overwrite": 0,
+                "span.Second level.field_overwrite": 1,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn reserved_fields_are_nested() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_reserved_field_policy(ReservedFieldPolicy::Nest);
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", target = "from span").entered();
+            tracing::info!(timestamp = 1, other = 2, "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "other": 2,
+                "fields": {
+                    "target": "from span",
+                    "timestamp": 1,
+                },
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn renamed_and_removed_field_names() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_field_names(FieldNames {
+                target: Some("logger".to_string()),
+                log_level: Some("level".to_string()),
+                message: Some("msg".to_string()),
+                timestamp: None,
+                ..FieldNames::default()
+            });
+
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", span_field = 0).entered();
+            tracing::info!(event_field = 1.1, "FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        let event = iter.next().unwrap().as_object_mut().unwrap();
+        assert!(event
+            .remove("name")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .starts_with("event src/lib.rs:"));
+        assert_eq!(
+            serde_json::json!({
+                "logger": "tracing_json_span_fields::tests",
+                "level": "INFO",
+                "msg": "FOOBAR",
+                "event_field": 1.1,
+                "span_field": 0,
+            }),
+            Value::Object(event.clone())
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn renamed_message_is_reserved() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_reserved_field_policy(ReservedFieldPolicy::Nest)
+            .with_field_names(FieldNames {
+                message: Some("msg".to_string()),
+                ..FieldNames::default()
+            });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", msg = "from span").entered();
+            tracing::info!("FOOBAR");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "msg": "FOOBAR",
+                "fields": {
+                    "msg": "from span",
+                },
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn optional_fields_keep_custom_names() {
+        let layer = JsonLayer::default()
+            .with_field_names(FieldNames {
+                file: Some("src_file".to_string()),
+                span_id: Some("span".to_string()),
+                ..FieldNames::default()
+            })
+            .with_source_location(true)
+            .with_span_ids(false)
+            .with_thread_info(true)
+            .with_thread_info(false);
+
+        let names = &layer.options.field_names;
+        assert_eq!(Some("src_file"), names.file.as_deref());
+        assert_eq!(Some("line"), names.line.as_deref());
+        assert_eq!(Some("span"), names.span_id.as_deref());
+        assert_eq!(None, names.thread_id);
+    }
+
+    #[test]
+    fn source_location_and_thread_info() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_source_location(true)
+            .with_thread_info(true);
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        let line = std::thread::Builder::new()
+            .name("logging thread".to_string())
+            .spawn(|| {
+                with_default(subscriber, || {
+                    tracing::info!("FOOBAR");
+                    line!() - 1
+                })
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        let event = iter.next().unwrap();
+        let thread_id = event.as_object_mut().unwrap().remove("thread_id");
+        assert!(thread_id.unwrap().is_u64(), "thread_id should be a number");
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "FOOBAR",
+                "file": "src/lib.rs",
+                "line": line,
+                "module_path": "tracing_json_span_fields::tests",
+                "thread_name": "logging thread",
+            }),
+            "event src/lib.rs:",
+            &before,
+            event,
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn span_ids_and_path() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_span_ids(true);
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        let ids = with_default(subscriber, || {
+            let span1 = tracing::info_span!("Top level").entered();
+            tracing::info!("ONE");
+            let span2 = tracing::info_span!("Second level").entered();
+            let span3 = tracing::info_span!("Third level").entered();
+            tracing::info!("THREE");
+            [span1.id(), span2.id(), span3.id()].map(|id| id.unwrap().into_u64())
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "ONE",
+                "span_id": ids[0],
+                "root_span_id": ids[0],
+                "span_path": ["Top level"],
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "THREE",
+                "span_id": ids[2],
+                "parent_span_id": ids[1],
+                "root_span_id": ids[0],
+                "span_path": ["Top level", "Second level", "Third level"],
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn elapsed_ms() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let start = datetime!(2023-07-25 09:53:01 UTC);
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_clock(clock::SteppingClock::new(start, time::Duration::SECOND))
+            .with_elapsed_ms(true);
+
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            tracing::info!("NO SPANS");
+            let _span1 = tracing::info_span!("Top level").entered();
+            let _span2 = tracing::info_span!("Second level").entered();
+            tracing::info!("TWO SPANS");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "NO SPANS",
+            }),
+            "event src/lib.rs:",
+            &start,
+            iter.next().unwrap(),
+        );
+        // The monotonic reading of the clock is taken when each span is created and when each
+        // event is written
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "TWO SPANS",
+                "elapsed_ms": 1000.0,
+                "root_elapsed_ms": 2000.0,
+            }),
+            "event src/lib.rs:",
+            &start,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn span_close_events() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let start = datetime!(2023-07-25 09:53:01 UTC);
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_clock(clock::SteppingClock::new(start, time::Duration::SECOND))
+            .with_span_close_events(true);
+
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            let _parent = tracing::info_span!("Parent", parent_field = 0).entered();
+            let span = tracing::info_span!("Child", child_field = 1);
+            span.in_scope(|| {});
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        // The monotonic reading of the clock advances a second whenever a span is created,
+        // entered, exited or closed
+        for (name, busy, idle, expected) in [
+            (
+                "Child",
+                1,
+                2,
+                serde_json::json!({
+                    "target": "tracing_json_span_fields::tests",
+                    "log_level": "INFO",
+                    "message": "close",
+                    "parent_field": 0,
+                    "child_field": 1,
+                }),
+            ),
+            (
+                "Parent",
+                5,
+                2,
+                serde_json::json!({
+                    "target": "tracing_json_span_fields::tests",
+                    "log_level": "INFO",
+                    "message": "close",
+                    "parent_field": 0,
+                }),
+            ),
+        ] {
+            let record = iter.next().unwrap();
+            let fields = record.as_object_mut().unwrap();
+            let duration = fields.remove("duration_ns").unwrap().as_u64().unwrap();
+            let busy_ns = fields.remove("busy_ns").unwrap().as_u64().unwrap();
+            let idle_ns = fields.remove("idle_ns").unwrap().as_u64().unwrap();
+            assert_eq!(busy * 1_000_000_000, busy_ns, "{} busy", name);
+            assert_eq!(idle * 1_000_000_000, idle_ns, "{} idle", name);
+            assert_eq!(duration, busy_ns + idle_ns, "{}", name);
+
+            assert_json_timestamp_name(expected, name, &start, record);
+        }
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn static_and_dynamic_fields() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let counter = std::sync::atomic::AtomicU64::new(0);
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_static_fields(
+                serde_json::json!({"service": "test", "version": "1.0"})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            )
+            .with_dynamic_field("counter", move || {
+                counter
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                    .into()
+            });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            tracing::info!("ONE");
+            let _span = tracing::info_span!("A span", version = "2.0").entered();
+            tracing::info!("TWO");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "ONE",
+                "service": "test",
+                "version": "1.0",
+                "counter": 0,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "TWO",
+                "service": "test",
+                "version": "2.0",
+                "counter": 1,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn redacted_fields() {
+        use redact::{FieldPattern, ValuePattern};
+
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_redaction(
+                Redaction::new()
+                    .field(FieldPattern::glob("*password*"))
+                    .value(ValuePattern::bearer_tokens()),
+            );
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "A span",
+                db_password = "hunter2",
+                user = "bob",
+                auth = tracing::field::Empty
+            )
+            .entered();
+            span.record("auth", "Bearer abc123");
+            tracing::info!(password = 42, "Sent Bearer xyz to {}", "server");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "Sent [REDACTED] to server",
+                "db_password": "[REDACTED]",
+                "password": "[REDACTED]",
+                "user": "bob",
+                "auth": "[REDACTED]",
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[cfg(feature = "pseudonymize")]
+    #[test]
+    fn pseudonymized_fields() {
+        use pseudonymize::Pseudonymizer;
+        use redact::FieldPattern;
+
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_redaction(Redaction::new().field(FieldPattern::exact("secret_id")))
+            .with_pseudonymizer(
+                Pseudonymizer::new("k1", "key")
+                    .field(FieldPattern::glob("*_id"))
+                    .hash_len(4),
+            );
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", user_id = "alice").entered();
+            let account = serde_json::json!({"owner_id": "alice", "secret_id": 2});
+            tracing::info!(
+                other_id = "alice",
+                secret_id = 1,
+                account = ?AsJson(&account),
+                "ONE"
+            );
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
+        let pseudonym = Pseudonymizer::new("k1", "key")
+            .hash_len(4)
+            .pseudonymize(&"alice".into());
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "ONE",
+                "user_id": pseudonym,
+                "other_id": pseudonym,
+                "secret_id": "[REDACTED]",
+                "account": {"owner_id": pseudonym, "secret_id": "[REDACTED]"},
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[cfg(all(tracing_unstable, feature = "valuable"))]
+    #[test]
+    fn valuable_fields() {
+        use std::collections::BTreeMap;
 
         let data = Arc::new(Mutex::new(vec![]));
         let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
 
         let subscriber = Registry::default().with(layer);
 
-        tracing::info!("BEFORE");
-
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let _span1 = tracing::info_span!("Top level", field_top = 0).entered();
-            tracing::info!(field_event = "from event", "FOOBAR");
-            tracing::error!("BAZ");
+            let ids = vec![1, 2];
+            let _span = tracing::info_span!("A span", ids = field::valuable(&ids)).entered();
+            let headers = BTreeMap::from([("accept", vec!["json"]), ("host", vec![])]);
+            let pair = ("a", true);
+            tracing::info!(
+                headers = field::valuable(&headers),
+                pair = field::valuable(&pair),
+                "ONE"
+            );
         });
 
-        tracing::info!("AFTER");
-
         let mut data = data.lock().unwrap();
         let mut iter = (*data).iter_mut();
 
@@ -399,20 +2478,66 @@ mod tests {
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "FOOBAR",
-                "field_top": 0,
-                "field_event": "from event"
+                "message": "ONE",
+                "ids": [1, 2],
+                "headers": {"accept": ["json"], "host": []},
+                "pair": ["a", true],
             }),
             "event src/lib.rs:",
             &before,
             iter.next().unwrap(),
         );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn nested_json_values_are_protected() {
+        use redact::FieldPattern;
+
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_redaction(Redaction::new().field(FieldPattern::exact("password")))
+            .with_limits(Limits {
+                max_string_len: Some(10),
+                ..Limits::default()
+            })
+            .with_json_field_suffix(".json");
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let login = serde_json::json!({
+                "password": "x",
+                "users": [{"name": "bob", "password": "hunter2"}],
+                "note": "0123456789abcdef",
+            });
+            tracing::info!(
+                login = ?AsJson(&login),
+                "body.json" = r#"{"password": "y", "tags": ["0123456789abc"]}"#,
+                "ONE"
+            );
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
         assert_json_timestamp_name(
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
-                "log_level": "ERROR",
-                "message": "BAZ",
-                "field_top": 0,
+                "log_level": "INFO",
+                "message": "ONE",
+                "login": {
+                    "password": "[REDACTED]",
+                    "users": [{"name": "bob", "password": "[REDACTED]"}],
+                    "note": "0123456789…[truncated 6 bytes]",
+                },
+                "body.json": {
+                    "password": "[REDACTED]",
+                    "tags": ["0123456789…[truncated 3 bytes]"],
+                },
             }),
             "event src/lib.rs:",
             &before,
@@ -422,18 +2547,27 @@ mod tests {
     }
 
     #[test]
-    fn two_spans_different_fields() {
+    fn json_fields() {
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_json_field_suffix(".json");
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let _span1 = tracing::info_span!("Top level", field_top = 0).entered();
-            let _span2 = tracing::info_span!("Second level", field_second = 1).entered();
-            tracing::info!(field_event = "from event", "FOOBAR");
+            let _span = tracing::info_span!("A span", "span.json" = "[1, 2]").entered();
+            let payload = serde_json::json!({"nested": {"list": [true]}});
+            tracing::info!(
+                payload = ?AsJson(&payload),
+                "valid.json" = r#"{"a": "b"}"#,
+                "invalid.json" = "{oops",
+                plain = r#"{"a": "b"}"#,
+                "ONE"
+            );
+            tracing::info!("{:?}", AsJson(&payload));
         });
 
         let mut data = data.lock().unwrap();
@@ -443,10 +2577,23 @@ mod tests {
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "FOOBAR",
-                "field_top": 0,
-                "field_second": 1,
-                "field_event": "from event"
+                "message": "ONE",
+                "span.json": [1, 2],
+                "payload": {"nested": {"list": [true]}},
+                "valid.json": {"a": "b"},
+                "invalid.json": "{oops",
+                "plain": r#"{"a": "b"}"#,
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": r#"{"nested":{"list":[true]}}"#,
+                "span.json": [1, 2],
             }),
             "event src/lib.rs:",
             &before,
@@ -456,18 +2603,20 @@ mod tests {
     }
 
     #[test]
-    fn two_spans_same_fields() {
+    fn expanded_dotted_names() {
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_expanded_dotted_names(true);
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let _span1 = tracing::info_span!("Top level", field_overwrite = 0).entered();
-            let _span2 = tracing::info_span!("Second level", field_overwrite = 1).entered();
-            tracing::info!(field_event = "from event", "FOOBAR");
+            let _span =
+                tracing::info_span!("A span", http.method = "GET", http.route = "/").entered();
+            tracing::info!(http.status_code = 200, http = "1.1", "a..b" = 1, "ONE");
         });
 
         let mut data = data.lock().unwrap();
@@ -477,9 +2626,14 @@ mod tests {
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "FOOBAR",
-                "field_overwrite": 1,
-                "field_event": "from event"
+                "message": "ONE",
+                "http": {
+                    "_value": "1.1",
+                    "method": "GET",
+                    "route": "/",
+                    "status_code": 200,
+                },
+                "a..b": 1,
             }),
             "event src/lib.rs:",
             &before,
@@ -489,18 +2643,24 @@ mod tests {
     }
 
     #[test]
-    fn two_spans_same_fields_including_event() {
+    fn expanded_json_fields() {
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_json_field_suffix(".json")
+            .with_expanded_dotted_names(true);
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let _span1 = tracing::info_span!("Top level", field_overwrite = 0).entered();
-            let _span2 = tracing::info_span!("Second level", field_overwrite = 1).entered();
-            tracing::info!(field_overwrite = "from event", "FOOBAR");
+            tracing::info!(
+                "headers.json" = r#"{"accept": "*/*"}"#,
+                "http.body.json" = "[1, 2]",
+                http.method = "POST",
+                "ONE"
+            );
         });
 
         let mut data = data.lock().unwrap();
@@ -510,8 +2670,9 @@ mod tests {
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "FOOBAR",
-                "field_overwrite": "from event"
+                "message": "ONE",
+                "headers": {"accept": "*/*"},
+                "http": {"body": [1, 2], "method": "POST"},
             }),
             "event src/lib.rs:",
             &before,
@@ -521,19 +2682,41 @@ mod tests {
     }
 
     #[test]
-    fn two_events_from_two_spans() {
+    fn filtered_fields() {
+        use filter::FieldScope;
+        use redact::FieldPattern;
+
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_field_filter(
+                FieldFilter::new()
+                    .allow(
+                        "tracing_json_span_fields",
+                        FieldScope::Spans,
+                        [FieldPattern::exact("user_id")],
+                    )
+                    .deny(
+                        "tracing_json_span_fields",
+                        FieldScope::Events,
+                        [FieldPattern::exact("sql.params")],
+                    ),
+            );
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let _span1 = tracing::info_span!("Top level", field_top = 0).entered();
-            tracing::info!(field_event = "from event one", "ONE");
-            let _span2 = tracing::info_span!("Second level", field_second = 1).entered();
-            tracing::info!(field_event = "from event two", "TWO");
+            let span = tracing::info_span!(
+                "A span",
+                user_id = 1,
+                noise = 2,
+                late = tracing::field::Empty
+            )
+            .entered();
+            span.record("late", 3);
+            tracing::info!(sql.params = "secret", rows = 4, "Query");
         });
 
         let mut data = data.lock().unwrap();
@@ -543,22 +2726,48 @@ mod tests {
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "ONE",
-                "field_top": 0,
-                "field_event": "from event one"
+                "message": "Query",
+                "user_id": 1,
+                "rows": 4,
             }),
             "event src/lib.rs:",
             &before,
             iter.next().unwrap(),
         );
+        assert_eq!(None, iter.next(), "No more logged events");
+    }
+
+    #[test]
+    fn limited_records() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_limits(Limits {
+                max_string_len: Some(10),
+                max_fields: Some(2),
+                ..Limits::default()
+            });
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", a = "0123456789abcdef", b = 2).entered();
+            tracing::info!(c = 3, "ONE");
+        });
+
+        let mut data = data.lock().unwrap();
+        let mut iter = (*data).iter_mut();
+
         assert_json_timestamp_name(
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "TWO",
-                "field_top": 0,
-                "field_second": 1,
-                "field_event": "from event two"
+                "message": "ONE",
+                "a": "0123456789…[truncated 6 bytes]",
+                "c": 3,
+                "dropped_fields": 1,
             }),
             "event src/lib.rs:",
             &before,
@@ -568,19 +2777,23 @@ mod tests {
     }
 
     #[test]
-    fn one_span_recorded_field() {
+    fn oversized_records() {
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_limits(Limits {
+                max_record_size: Some(300),
+                oversize_policy: OversizePolicy::DropWithWarning,
+                ..Limits::default()
+            });
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let span = tracing::info_span!("A span", span_field = 0, recorded_field = field::Empty);
-            span.record("recorded_field", "foo");
-            let _span = span.entered();
-            tracing::info!(event_field = 1.1, "FOOBAR");
+            tracing::info!(a = 1, "ONE");
+            tracing::info!(a = 1, "{}", "TWO ".repeat(100));
         });
 
         let mut data = data.lock().unwrap();
@@ -590,63 +2803,92 @@ mod tests {
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
-                "message": "FOOBAR",
-                "event_field": 1.1,
-                "recorded_field": "foo",
-                "span_field": 0,
+                "message": "ONE",
+                "a": 1,
             }),
             "event src/lib.rs:",
             &before,
             iter.next().unwrap(),
         );
+        let record = iter.next().unwrap();
+        let record_size = record
+            .as_object_mut()
+            .unwrap()
+            .remove("record_size")
+            .unwrap();
+        assert!(record_size.as_u64().unwrap() > 400);
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "WARN",
+                "message": format!("record of {} bytes exceeds the limit of 300 bytes", record_size),
+            }),
+            "event src/lib.rs:",
+            &before,
+            record,
+        );
         assert_eq!(None, iter.next(), "No more logged events");
     }
 
     #[test]
-    fn one_span_recorded_field_overwriting_initial_field() {
+    fn oversized_built_in_fields() {
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_limits(Limits {
+                max_record_size: Some(120),
+                ..Limits::default()
+            });
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            let span = tracing::info_span!("A span", span_field = 0);
-            span.record("span_field", "foo");
-            let _span = span.entered();
-            tracing::info!(event_field = 1.1, "FOOBAR");
+            tracing::info!(a = 1, "ONE");
         });
 
         let mut data = data.lock().unwrap();
         let mut iter = (*data).iter_mut();
 
+        let record = iter.next().unwrap();
+        let record_size = record
+            .as_object_mut()
+            .unwrap()
+            .remove("record_size")
+            .unwrap();
+        assert!(record_size.as_u64().unwrap() > 120);
         assert_json_timestamp_name(
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
-                "log_level": "INFO",
-                "message": "FOOBAR",
-                "event_field": 1.1,
-                "span_field": "foo",
+                "log_level": "WARN",
+                "message": format!("record of {} bytes exceeds the limit of 120 bytes", record_size),
             }),
             "event src/lib.rs:",
             &before,
-            iter.next().unwrap(),
+            record,
         );
         assert_eq!(None, iter.next(), "No more logged events");
     }
 
     #[test]
-    fn without_any_spans() {
+    fn nested_span_format() {
         let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default().with_output(TestOutput { data: data.clone() });
+        let layer = JsonLayer::default()
+            .with_output(TestOutput { data: data.clone() })
+            .with_span_format(SpanFormat::Nested);
 
         let subscriber = Registry::default().with(layer);
 
         let before = OffsetDateTime::now_utc();
 
         with_default(subscriber, || {
-            tracing::info!(event_field = 1.1, "FOOBAR");
+            {
+                let _span1 = tracing::info_span!("Top level", field_overwrite = 0).entered();
+                let _span2 = tracing::info_span!("Second level", field_overwrite = 1).entered();
+                tracing::info!(field_event = "from event", "FOOBAR");
+            }
+            tracing::info!("NO SPANS");
         });
 
         let mut data = data.lock().unwrap();
@@ -657,7 +2899,31 @@ mod tests {
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
                 "message": "FOOBAR",
-                "event_field": 1.1,
+                "field_event": "from event",
+                "span": {
+                    "name": "Second level",
+                    "fields": {"field_overwrite": 1},
+                },
+                "spans": [
+                    {
+                        "name": "Top level",
+                        "fields": {"field_overwrite": 0},
+                    },
+                    {
+                        "name": "Second level",
+                        "fields": {"field_overwrite": 1},
+                    },
+                ],
+            }),
+            "event src/lib.rs:",
+            &before,
+            iter.next().unwrap(),
+        );
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "NO SPANS",
             }),
             "event src/lib.rs:",
             &before,
@@ -667,14 +2933,54 @@ mod tests {
     }
 
     #[test]
-    fn custom_timestamp() {
-        let timestamp_format = format_description!(
-            "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]"
+    fn writer_output() {
+        let buffer = BufferWriter::default();
+        let layer = JsonLayer::default().with_output(JsonWriter::new(buffer.clone()));
+
+        let subscriber = Registry::default().with(layer);
+
+        let before = OffsetDateTime::now_utc();
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", span_field = 0).entered();
+            tracing::info!(event_field = 1.1, "ONE");
+            tracing::warn!("TWO");
+        });
+
+        let buffer = buffer.0.lock().unwrap();
+        let output = std::str::from_utf8(&buffer).unwrap();
+        let mut lines = output.lines();
+
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "INFO",
+                "message": "ONE",
+                "event_field": 1.1,
+                "span_field": 0,
+            }),
+            "event src/lib.rs:",
+            &before,
+            &mut serde_json::from_str(lines.next().unwrap()).unwrap(),
         );
-        let data = Arc::new(Mutex::new(vec![]));
-        let layer = JsonLayer::default()
-            .with_output(TestOutput { data: data.clone() })
-            .with_timestamp_format(timestamp_format);
+        assert_json_timestamp_name(
+            serde_json::json!({
+                "target": "tracing_json_span_fields::tests",
+                "log_level": "WARN",
+                "message": "TWO",
+                "span_field": 0,
+            }),
+            "event src/lib.rs:",
+            &before,
+            &mut serde_json::from_str(lines.next().unwrap()).unwrap(),
+        );
+        assert_eq!(None, lines.next(), "No more logged lines");
+    }
+
+    #[test]
+    fn pretty_writer_output() {
+        let buffer = BufferWriter::default();
+        let layer = JsonLayer::default().with_output(JsonWriter::pretty(buffer.clone()));
 
         let subscriber = Registry::default().with(layer);
 
@@ -684,10 +2990,15 @@ mod tests {
             tracing::info!(event_field = 1.1, "FOOBAR");
         });
 
-        let mut data = data.lock().unwrap();
-        let mut iter = (*data).iter_mut();
+        let buffer = buffer.0.lock().unwrap();
+        let output = std::str::from_utf8(&buffer).unwrap();
 
-        assert_json_timestamp_name_with_format(
+        assert!(
+            output.starts_with("{\n  \""),
+            "output should be pretty printed"
+        );
+        assert!(output.ends_with("}\n"), "output should end with a newline");
+        assert_json_timestamp_name(
             serde_json::json!({
                 "target": "tracing_json_span_fields::tests",
                 "log_level": "INFO",
@@ -696,10 +3007,109 @@ mod tests {
             }),
             "event src/lib.rs:",
             &before,
-            iter.next().unwrap(),
-            &timestamp_format,
+            &mut serde_json::from_str(output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn output_errors_are_handled() {
+        let errors = Arc::new(Mutex::new(vec![]));
+        let handler_errors = errors.clone();
+        let layer = JsonLayer::default()
+            .with_output(FailingOutput)
+            .with_error_handler(move |e| handler_errors.lock().unwrap().push(e.to_string()));
+
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            tracing::info!("ONE");
+            tracing::info!("TWO");
+        });
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(
+            vec![
+                "failed to write output: broken pipe",
+                "failed to write output: broken pipe"
+            ],
+            *errors
+        );
+    }
+
+    #[test]
+    fn fixed_clock_output() {
+        let buffer = BufferWriter::default();
+        let layer = JsonLayer::default()
+            .with_output(JsonWriter::new(buffer.clone()))
+            .with_clock(clock::FixedClock(
+                datetime!(2023-07-25 09:53:01.790152227 UTC),
+            ))
+            .with_field_names(FieldNames {
+                name: None,
+                ..FieldNames::default()
+            });
+
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            let _span = tracing::info_span!("A span", span_field = 42).entered();
+            tracing::info!(logged_message_field = "value", "Logged message");
+        });
+
+        let buffer = buffer.0.lock().unwrap();
+        #[cfg(not(feature = "preserve_order"))]
+        let expected = concat!(
+            r#"{"log_level":"INFO","logged_message_field":"value","message":"Logged message","#,
+            r#""span_field":42,"target":"tracing_json_span_fields::tests","#,
+            r#""timestamp":"2023-07-25T09:53:01.790152227Z"}"#,
+            "\n"
+        );
+        #[cfg(feature = "preserve_order")]
+        let expected = concat!(
+            r#"{"timestamp":"2023-07-25T09:53:01.790152227Z","log_level":"INFO","#,
+            r#""message":"Logged message","target":"tracing_json_span_fields::tests","#,
+            r#""span_field":42,"logged_message_field":"value"}"#,
+            "\n"
+        );
+        assert_eq!(expected, std::str::from_utf8(&buffer).unwrap());
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn preserved_order() {
+        let buffer = BufferWriter::default();
+        let layer = JsonLayer::default()
+            .with_output(JsonWriter::new(buffer.clone()))
+            .with_clock(clock::FixedClock(datetime!(2023-07-25 09:53:01 UTC)))
+            .with_static_fields(
+                serde_json::json!({"service": "test"})
+                    .as_object()
+                    .unwrap()
+                    .clone(),
+            )
+            .with_field_names(FieldNames {
+                name: None,
+                target: None,
+                ..FieldNames::default()
+            });
+
+        let subscriber = Registry::default().with(layer);
+
+        with_default(subscriber, || {
+            let _outer = tracing::info_span!("Outer", z = 1, a = 2).entered();
+            let _inner = tracing::info_span!("Inner", y = 3, a = 4).entered();
+            tracing::info!(x = 5, b = 6, z = 7, "Logged message");
+        });
+
+        let buffer = buffer.0.lock().unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"timestamp":"2023-07-25T09:53:01.000000000Z","log_level":"INFO","#,
+                r#""message":"Logged message","service":"test","z":7,"a":4,"y":3,"x":5,"b":6}"#,
+                "\n"
+            ),
+            std::str::from_utf8(&buffer).unwrap()
         );
-        assert_eq!(None, iter.next(), "No more logged events");
     }
 
     #[test]
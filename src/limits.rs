@@ -0,0 +1,201 @@
+use crate::record::JsonRecord;
+use serde_json::{Map, Value};
+
+/// The name of the field holding the number of fields dropped by [`Limits::max_fields`].
+pub(crate) const DROPPED_FIELDS: &str = "dropped_fields";
+
+/// Limits on the size of the records written by a [`JsonLayer`](crate::JsonLayer), to keep log
+/// shippers from rejecting them. No limits are set by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum length in bytes of strings recorded for fields of spans and events, also
+    /// within nested objects and arrays. Longer strings are cut off and end with
+    /// `…[truncated N bytes]`.
+    pub max_string_len: Option<usize>,
+    /// The maximum number of fields of spans and events in a record, not counting the message.
+    /// The fields of the event are kept first, then those of the spans and the static and
+    /// dynamic fields in the order they would have been written. The remaining fields are
+    /// dropped and their number is written as `dropped_fields`.
+    pub max_fields: Option<usize>,
+    /// The maximum size in bytes of a serialized record, not including the trailing newline.
+    pub max_record_size: Option<usize>,
+    /// What happens to records larger than `max_record_size`.
+    pub oversize_policy: OversizePolicy,
+}
+
+/// What happens to a record larger than [`Limits::max_record_size`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// The largest fields of spans and events are truncated, or converted to a string and
+    /// truncated, until the record fits. Fields too short to be shortened by truncating them
+    /// are dropped instead. The fields added by the layer are kept, and if these alone are
+    /// too large, the record is replaced like with [`OversizePolicy::DropWithWarning`].
+    #[default]
+    TruncateLargest,
+    /// The record is replaced by a warning with the fields added by the layer, the size of the
+    /// record as `record_size`, and a level of `WARN`.
+    DropWithWarning,
+}
+
+/// Shortens `s` to at most `max_len` bytes plus a marker saying how much was cut off.
+pub(crate) fn truncate_string(mut s: String, max_len: usize) -> String {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    let truncated = s.len() - end;
+    s.truncate(end);
+    s.push_str(&format!("…[truncated {} bytes]", truncated));
+    s
+}
+
+/// Shortens the strings in `value`, also within nested objects and arrays, to at most
+/// `max_len` bytes plus a marker.
+pub(crate) fn truncate_strings(value: &mut Value, max_len: usize) {
+    match value {
+        Value::String(s) => *s = truncate_string(std::mem::take(s), max_len),
+        Value::Array(values) => {
+            for value in values {
+                truncate_strings(value, max_len);
+            }
+        }
+        Value::Object(fields) => {
+            for value in fields.values_mut() {
+                truncate_strings(value, max_len);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Keeps `max_fields` fields, not counting the message, and returns how many were dropped.
+/// Fields for which `is_event` is true are kept first, then the others in order.
+pub(crate) fn limit_fields(
+    fields: &mut JsonRecord<'_>,
+    max_fields: usize,
+    is_event: impl Fn(&str) -> bool,
+) -> usize {
+    let (event, others): (Vec<&str>, Vec<&str>) = fields
+        .keys()
+        .filter(|key| *key != "message")
+        .partition(|key| is_event(key));
+    let dropped: Vec<String> = event
+        .into_iter()
+        .chain(others)
+        .skip(max_fields)
+        .map(str::to_string)
+        .collect();
+    for key in &dropped {
+        fields.remove(key);
+    }
+    dropped.len()
+}
+
+/// The length of `value` when serialized.
+pub(crate) fn serialized_len(value: &impl serde::Serialize) -> usize {
+    struct Counter(usize);
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    // Serializing a Value into a writer that never fails cannot fail
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+/// Truncates the largest fields not in `kept`, until `fields` serialize to at most `max_size`
+/// bytes, removing fields that truncating doesn't shorten. Returns whether that was possible.
+pub(crate) fn truncate_largest(
+    fields: &mut Map<String, Value>,
+    kept: &[&str],
+    max_size: usize,
+) -> bool {
+    loop {
+        let size = serialized_len(fields);
+        if size <= max_size {
+            return true;
+        }
+        let excess = size - max_size;
+
+        let largest = fields
+            .iter()
+            .filter(|(key, _)| !kept.contains(&key.as_str()))
+            .map(|(key, value)| (serialized_len(value), key))
+            .max();
+        let Some((len, key)) = largest else {
+            return false;
+        };
+        let key = key.clone();
+
+        let value = fields.get_mut(&key).expect("the largest field exists");
+        let text = match value.take() {
+            Value::String(s) => s,
+            value => value.to_string(),
+        };
+        // At most the whole text is cut off, so this is at least as long as the marker
+        let marker_len = format!("…[truncated {} bytes]", text.len()).len();
+        let max_len = text.len().saturating_sub(excess + marker_len);
+        *value = truncate_string(text, max_len).into();
+        // Always make progress, even if the marker is longer than what it replaces
+        if serialized_len(value) >= len {
+            remove_field(fields, &key);
+        }
+    }
+}
+
+/// Removes a field, keeping the order of the remaining fields with the `preserve_order` feature.
+fn remove_field(fields: &mut Map<String, Value>, key: &str) -> Option<Value> {
+    #[cfg(feature = "preserve_order")]
+    return fields.shift_remove(key);
+    #[cfg(not(feature = "preserve_order"))]
+    return fields.remove(key);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn truncate() {
+        assert_eq!("short", truncate_string("short".to_string(), 5));
+        assert_eq!(
+            "abc…[truncated 3 bytes]",
+            truncate_string("abcdef".to_string(), 3)
+        );
+        assert_eq!(
+            "a…[truncated 4 bytes]",
+            truncate_string("aäö".to_string(), 2),
+            "cut at a character boundary"
+        );
+    }
+
+    #[test]
+    fn largest_fields_are_truncated() {
+        let mut value = json!({
+            "timestamp": "x".repeat(20),
+            "big": "y".repeat(200),
+            "object": {"list": [1, 2, 3]},
+            "small": 1,
+        });
+        let fields = value.as_object_mut().unwrap();
+        assert!(truncate_largest(fields, &["timestamp"], 120));
+        assert!(serialized_len(fields) <= 120);
+        assert_eq!(json!("x".repeat(20)), fields["timestamp"]);
+        assert!(fields["big"].as_str().unwrap().contains("…[truncated"));
+        assert_eq!(json!(1), fields["small"]);
+
+        assert!(!truncate_largest(fields, &["timestamp"], 10));
+        assert_eq!(1, fields.len(), "only the kept field is left");
+    }
+}
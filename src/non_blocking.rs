@@ -0,0 +1,653 @@
+//! A [`JsonOutput`] that writes from a background thread.
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::{default_error_handler, ErrorHandler};
+use crate::timestamp::TimestampFormat;
+use crate::{Error, FieldNames, JsonOutput};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Iso8601;
+
+/// What a [`NonBlockingOutput`] does with an event when its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the background thread to make room in the queue.
+    Block,
+    /// Drop the event being written.
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued event to make room for the one being written.
+    DropOldest,
+}
+
+/// A [`JsonOutput`] handing events to a dedicated thread which writes them to another
+/// [`JsonOutput`].
+///
+/// The events are passed through a bounded queue. When the queue is full the
+/// [`OverflowPolicy`] decides what happens. Dropped events are counted, and the count is
+/// periodically written to the wrapped output as a warning record, using the field names,
+/// clock and timestamp format set on the [`NonBlockingBuilder`]. These should match those of
+/// the [`JsonLayer`](crate::JsonLayer).
+///
+/// If the background thread stops, for example because the wrapped output panics, writing
+/// returns an error instead of waiting for room in the queue.
+///
+/// The returned [`WorkerGuard`] must be kept alive for as long as events should be written.
+/// Dropping it writes all queued events and stops the thread.
+///
+/// ```
+/// use tracing_subscriber::prelude::*;
+/// use tracing_json_span_fields::non_blocking::NonBlockingOutput;
+/// use tracing_json_span_fields::{JsonLayer, JsonStdout};
+/// let (output, _guard) = NonBlockingOutput::new(JsonStdout::default());
+/// tracing_subscriber::registry().with(JsonLayer::default().with_output(output)).init();
+/// tracing::info!("Logged from the background thread");
+/// ```
+pub struct NonBlockingOutput {
+    shared: Arc<Shared>,
+    policy: OverflowPolicy,
+    /// Whether the wrapped output accepts serialized events
+    accepts_bytes: bool,
+}
+
+impl NonBlockingOutput {
+    /// Starts a background thread writing to `output` using the default configuration.
+    pub fn new<O>(output: O) -> (NonBlockingOutput, WorkerGuard)
+    where
+        O: JsonOutput + Send + 'static,
+    {
+        NonBlockingOutput::builder().finish(output)
+    }
+
+    pub fn builder() -> NonBlockingBuilder {
+        NonBlockingBuilder::default()
+    }
+
+    /// The total number of events dropped because the queue was full.
+    pub fn dropped_events(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl JsonOutput for NonBlockingOutput {
+    fn write(&self, value: Value) -> Result<(), Error> {
+        self.shared.push(Queued::Value(value), self.policy)
+    }
+
+    fn write_bytes(&self, line: &[u8]) -> Result<(), Error> {
+        self.shared.push(Queued::Line(line.to_vec()), self.policy)
+    }
+
+    fn accepts_bytes(&self) -> bool {
+        self.accepts_bytes
+    }
+}
+
+/// The shortest interval between reports of dropped events, to keep the worker thread from
+/// spinning.
+const MIN_DROPPED_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Configuration for a [`NonBlockingOutput`].
+pub struct NonBlockingBuilder {
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    dropped_report_interval: Duration,
+    thread_name: String,
+    on_error: ErrorHandler,
+    field_names: FieldNames,
+    clock: Box<dyn Clock + Send>,
+    timestamp_format: Box<dyn TimestampFormat + Send>,
+}
+
+impl Default for NonBlockingBuilder {
+    fn default() -> Self {
+        NonBlockingBuilder {
+            queue_capacity: 128_000,
+            overflow_policy: OverflowPolicy::default(),
+            dropped_report_interval: Duration::from_secs(10),
+            thread_name: "tracing-json-writer".to_string(),
+            on_error: default_error_handler(),
+            field_names: FieldNames::default(),
+            clock: Box::new(SystemClock),
+            timestamp_format: Box::new(Iso8601::DEFAULT),
+        }
+    }
+}
+
+impl NonBlockingBuilder {
+    /// The maximum number of events waiting to be written.
+    pub fn queue_capacity(self, queue_capacity: usize) -> NonBlockingBuilder {
+        NonBlockingBuilder {
+            queue_capacity: queue_capacity.max(1),
+            ..self
+        }
+    }
+
+    pub fn overflow_policy(self, overflow_policy: OverflowPolicy) -> NonBlockingBuilder {
+        NonBlockingBuilder {
+            overflow_policy,
+            ..self
+        }
+    }
+
+    /// How often the number of dropped events is written to the output, if any were dropped.
+    /// Intervals shorter than 100 ms are raised to 100 ms.
+    pub fn dropped_report_interval(self, dropped_report_interval: Duration) -> NonBlockingBuilder {
+        NonBlockingBuilder {
+            dropped_report_interval: dropped_report_interval.max(MIN_DROPPED_REPORT_INTERVAL),
+            ..self
+        }
+    }
+
+    pub fn thread_name(self, thread_name: impl Into<String>) -> NonBlockingBuilder {
+        NonBlockingBuilder {
+            thread_name: thread_name.into(),
+            ..self
+        }
+    }
+
+    /// Sets the function called when the wrapped output fails, replacing the default of
+    /// reporting the error on stderr.
+    pub fn error_handler<H>(self, on_error: H) -> NonBlockingBuilder
+    where
+        H: Fn(Error) + Send + Sync + 'static,
+    {
+        NonBlockingBuilder {
+            on_error: Box::new(on_error),
+            ..self
+        }
+    }
+
+    /// Sets the names of the fields of the records reporting dropped events.
+    pub fn field_names(self, field_names: FieldNames) -> NonBlockingBuilder {
+        NonBlockingBuilder {
+            field_names,
+            ..self
+        }
+    }
+
+    /// Sets the [`Clock`] used for the timestamps of the records reporting dropped events.
+    pub fn clock<C>(self, clock: C) -> NonBlockingBuilder
+    where
+        C: Clock + Send + 'static,
+    {
+        NonBlockingBuilder {
+            clock: Box::new(clock),
+            ..self
+        }
+    }
+
+    /// Sets how the timestamps of the records reporting dropped events are written.
+    pub fn timestamp_format<F>(self, timestamp_format: F) -> NonBlockingBuilder
+    where
+        F: TimestampFormat + Send + 'static,
+    {
+        NonBlockingBuilder {
+            timestamp_format: Box::new(timestamp_format),
+            ..self
+        }
+    }
+
+    /// Starts the background thread writing to `output`.
+    pub fn finish<O>(self, output: O) -> (NonBlockingOutput, WorkerGuard)
+    where
+        O: JsonOutput + Send + 'static,
+    {
+        let shared = Arc::new(Shared::new(self.queue_capacity));
+        let accepts_bytes = output.accepts_bytes();
+        let worker = Worker {
+            shared: shared.clone(),
+            output,
+            on_error: self.on_error,
+            dropped_report_interval: self.dropped_report_interval,
+            dropped_reported: 0,
+            field_names: self.field_names,
+            clock: self.clock,
+            timestamp_format: self.timestamp_format,
+        };
+        let handle = std::thread::Builder::new()
+            .name(self.thread_name)
+            .spawn(move || worker.run())
+            .expect("failed to spawn the output thread");
+
+        (
+            NonBlockingOutput {
+                shared: shared.clone(),
+                policy: self.overflow_policy,
+                accepts_bytes,
+            },
+            WorkerGuard {
+                shared,
+                handle: Some(handle),
+            },
+        )
+    }
+}
+
+/// Keeps the background thread of a [`NonBlockingOutput`] running.
+///
+/// Dropping the guard writes all queued events, flushes the wrapped output and waits for the
+/// thread to finish.
+#[must_use = "dropping the guard stops the output thread"]
+pub struct WorkerGuard {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.not_empty.notify_one();
+        self.shared.not_full.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// An event waiting to be written, in the form accepted by the wrapped output.
+enum Queued {
+    Value(Value),
+    Line(Vec<u8>),
+}
+
+struct Queue {
+    records: VecDeque<Queued>,
+    shutdown: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    dropped: AtomicU64,
+}
+
+impl Shared {
+    fn new(capacity: usize) -> Self {
+        Shared {
+            queue: Mutex::new(Queue {
+                records: VecDeque::new(),
+                shutdown: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // The queue is always left in a consistent state, so a panic elsewhere is no reason to
+        // stop logging
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, record: Queued, policy: OverflowPolicy) -> Result<(), Error> {
+        let mut queue = self.lock();
+        while queue.records.len() >= self.capacity && !queue.shutdown {
+            match policy {
+                OverflowPolicy::Block => {
+                    queue = self.not_full.wait(queue).unwrap_or_else(|e| e.into_inner());
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                OverflowPolicy::DropOldest => {
+                    queue.records.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if queue.shutdown {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the output thread has been stopped",
+            )
+            .into());
+        }
+        queue.records.push_back(record);
+        drop(queue);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+struct Worker<O> {
+    shared: Arc<Shared>,
+    output: O,
+    on_error: ErrorHandler,
+    dropped_report_interval: Duration,
+    dropped_reported: u64,
+    field_names: FieldNames,
+    clock: Box<dyn Clock + Send>,
+    timestamp_format: Box<dyn TimestampFormat + Send>,
+}
+
+/// Marks the queue as shut down when the worker stops, also by panicking, so that writers get
+/// an error instead of waiting for room in the queue forever.
+struct ShutdownOnExit(Arc<Shared>);
+
+impl Drop for ShutdownOnExit {
+    fn drop(&mut self) {
+        self.0.lock().shutdown = true;
+        self.0.not_full.notify_all();
+    }
+}
+
+impl<O> Worker<O>
+where
+    O: JsonOutput,
+{
+    fn run(mut self) {
+        let _shutdown = ShutdownOnExit(self.shared.clone());
+        let mut next_report = Instant::now() + self.dropped_report_interval;
+        loop {
+            let (batch, shutdown) = {
+                let mut queue = self.shared.lock();
+                while queue.records.is_empty() && !queue.shutdown {
+                    let timeout = next_report.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        break;
+                    }
+                    queue = self
+                        .shared
+                        .not_empty
+                        .wait_timeout(queue, timeout)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                }
+                let batch: Vec<Queued> = queue.records.drain(..).collect();
+                (batch, queue.shutdown)
+            };
+            self.shared.not_full.notify_all();
+
+            let wrote = !batch.is_empty();
+            for record in batch {
+                self.write(record);
+            }
+
+            if shutdown || Instant::now() >= next_report {
+                self.report_dropped();
+                next_report = Instant::now() + self.dropped_report_interval;
+            }
+            if wrote || shutdown {
+                if let Err(e) = self.output.flush() {
+                    (self.on_error)(e);
+                }
+            }
+            if shutdown {
+                break;
+            }
+        }
+    }
+
+    fn write(&self, record: Queued) {
+        let result = match record {
+            Queued::Value(value) => self.output.write(value),
+            Queued::Line(line) => self.output.write_bytes(&line),
+        };
+        if let Err(e) = result {
+            (self.on_error)(e);
+        }
+    }
+
+    fn report_dropped(&mut self) {
+        let dropped = self.shared.dropped.load(Ordering::Relaxed);
+        let newly_dropped = dropped - self.dropped_reported;
+        if newly_dropped == 0 {
+            return;
+        }
+        self.dropped_reported = dropped;
+
+        // Written like an event by the layer, with the fields it adds first
+        let names = &self.field_names;
+        let mut record = Map::new();
+        if let Some(key) = &names.timestamp {
+            match self.timestamp_format.format_timestamp(self.clock.now()) {
+                Ok(timestamp) => {
+                    record.insert(key.clone(), timestamp);
+                }
+                Err(e) => (self.on_error)(e),
+            }
+        }
+        if let Some(key) = &names.log_level {
+            record.insert(key.clone(), "WARN".into());
+        }
+        if let Some(key) = &names.message {
+            let message = format!(
+                "{} events dropped because the output queue was full",
+                newly_dropped
+            );
+            record.insert(key.clone(), message.into());
+        }
+        if let Some(key) = &names.target {
+            record.insert(key.clone(), module_path!().into());
+        }
+        record.insert("dropped_events".to_string(), newly_dropped.into());
+        self.write(Queued::Value(record.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct TestOutput {
+        data: Arc<Mutex<Vec<Value>>>,
+    }
+
+    impl JsonOutput for TestOutput {
+        fn write(&self, value: Value) -> Result<(), Error> {
+            self.data.lock().unwrap().push(value);
+            Ok(())
+        }
+    }
+
+    /// Collects serialized events.
+    struct LineOutput {
+        lines: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl JsonOutput for LineOutput {
+        fn write(&self, value: Value) -> Result<(), Error> {
+            self.write_bytes(&crate::json_line(&value, false)?)
+        }
+
+        fn write_bytes(&self, line: &[u8]) -> Result<(), Error> {
+            self.lines.lock().unwrap().extend_from_slice(line);
+            Ok(())
+        }
+
+        fn accepts_bytes(&self) -> bool {
+            true
+        }
+    }
+
+    fn queued(shared: &Shared) -> Vec<Value> {
+        shared
+            .lock()
+            .records
+            .iter()
+            .map(|record| match record {
+                Queued::Value(value) => value.clone(),
+                Queued::Line(line) => serde_json::from_slice(line).unwrap(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_newest() {
+        let shared = Shared::new(2);
+        for i in 0..4 {
+            shared
+                .push(Queued::Value(json!(i)), OverflowPolicy::DropNewest)
+                .unwrap();
+        }
+
+        assert_eq!(vec![json!(0), json!(1)], queued(&shared));
+        assert_eq!(2, shared.dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let shared = Shared::new(2);
+        for i in 0..4 {
+            shared
+                .push(Queued::Value(json!(i)), OverflowPolicy::DropOldest)
+                .unwrap();
+        }
+
+        assert_eq!(vec![json!(2), json!(3)], queued(&shared));
+        assert_eq!(2, shared.dropped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn report_interval_is_clamped() {
+        let builder = NonBlockingOutput::builder().dropped_report_interval(Duration::ZERO);
+        assert_eq!(MIN_DROPPED_REPORT_INTERVAL, builder.dropped_report_interval);
+    }
+
+    #[test]
+    fn guard_writes_queued_events() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let (output, guard) = NonBlockingOutput::builder()
+            .overflow_policy(OverflowPolicy::Block)
+            .queue_capacity(1)
+            .finish(TestOutput { data: data.clone() });
+
+        for i in 0..100 {
+            output.write(json!(i)).unwrap();
+        }
+        drop(guard);
+
+        let expected: Vec<Value> = (0..100).map(|i| json!(i)).collect();
+        assert_eq!(expected, *data.lock().unwrap());
+        assert_eq!(0, output.dropped_events());
+        assert!(output.write(json!(100)).is_err(), "writing after shutdown");
+    }
+
+    #[test]
+    fn serialized_events_are_written_as_lines() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let (output, guard) = NonBlockingOutput::new(LineOutput {
+            lines: lines.clone(),
+        });
+
+        assert!(output.accepts_bytes());
+        output.write_bytes(b"{\"n\":1}\n").unwrap();
+        output.write_bytes(b"{\"n\":2}\n").unwrap();
+        drop(guard);
+
+        assert_eq!(
+            "{\"n\":1}\n{\"n\":2}\n",
+            std::str::from_utf8(&lines.lock().unwrap()).unwrap()
+        );
+    }
+
+    /// Panics on every write.
+    struct PanickingOutput;
+
+    impl JsonOutput for PanickingOutput {
+        fn write(&self, _value: Value) -> Result<(), Error> {
+            panic!("output failed");
+        }
+    }
+
+    #[test]
+    fn writes_fail_after_the_worker_panics() {
+        let (output, guard) = NonBlockingOutput::builder()
+            .overflow_policy(OverflowPolicy::Block)
+            .queue_capacity(1)
+            .finish(PanickingOutput);
+
+        // The first writes may be queued before the worker has stopped
+        let failed = (0..100).any(|i| output.write(json!(i)).is_err());
+        assert!(failed, "writing after the worker stopped");
+        drop(guard);
+    }
+
+    /// Builds a worker with one queued event and two dropped ones.
+    fn worker_with_dropped_events(
+        builder: NonBlockingBuilder,
+        data: Arc<Mutex<Vec<Value>>>,
+    ) -> Worker<TestOutput> {
+        let worker = Worker {
+            shared: Arc::new(Shared::new(1)),
+            output: TestOutput { data },
+            on_error: builder.on_error,
+            dropped_report_interval: builder.dropped_report_interval,
+            dropped_reported: 0,
+            field_names: builder.field_names,
+            clock: builder.clock,
+            timestamp_format: builder.timestamp_format,
+        };
+        for i in 0..3 {
+            worker
+                .shared
+                .push(Queued::Value(json!(i)), OverflowPolicy::DropNewest)
+                .unwrap();
+        }
+        worker
+    }
+
+    #[test]
+    fn dropped_events_are_reported() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let mut worker = worker_with_dropped_events(NonBlockingOutput::builder(), data.clone());
+
+        worker.report_dropped();
+        worker.report_dropped();
+
+        let mut data = data.lock().unwrap();
+        assert_eq!(1, data.len(), "nothing new to report the second time");
+        let record = data[0].as_object_mut().unwrap();
+        assert!(record.remove("timestamp").is_some());
+        assert_eq!(
+            json!({
+                "target": "tracing_json_span_fields::non_blocking",
+                "log_level": "WARN",
+                "message": "2 events dropped because the output queue was full",
+                "dropped_events": 2,
+            }),
+            Value::Object(record.clone())
+        );
+    }
+
+    #[test]
+    fn dropped_events_report_uses_field_names() {
+        let data = Arc::new(Mutex::new(vec![]));
+        let builder = NonBlockingOutput::builder()
+            .field_names(FieldNames {
+                target: None,
+                log_level: Some("level".to_string()),
+                timestamp: Some("ts".to_string()),
+                message: Some("msg".to_string()),
+                ..FieldNames::default()
+            })
+            .clock(crate::clock::FixedClock(
+                time::macros::datetime!(2020-01-01 0:00 UTC),
+            ))
+            .timestamp_format(crate::timestamp::Epoch::Seconds);
+        let mut worker = worker_with_dropped_events(builder, data.clone());
+
+        worker.report_dropped();
+
+        assert_eq!(
+            vec![json!({
+                "ts": 1577836800,
+                "level": "WARN",
+                "msg": "2 events dropped because the output queue was full",
+                "dropped_events": 2,
+            })],
+            *data.lock().unwrap()
+        );
+    }
+}
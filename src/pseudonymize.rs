@@ -0,0 +1,138 @@
+//! Replacing identifying fields with keyed hashes, which can still be joined across records.
+
+use crate::redact::FieldPattern;
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use std::fmt::Write;
+
+/// Replaces the values of fields with a name matching one of the [`FieldPattern`]s with an
+/// HMAC-SHA256 of the value, written as `<key id>:<hex digest>`.
+///
+/// The key id tells which key was used, so hashes are only compared when they were created
+/// with the same key. String values are hashed as they are and other values as JSON, so
+/// `42` and `"42"` have the same hash.
+///
+/// ```
+/// use tracing_json_span_fields::pseudonymize::Pseudonymizer;
+/// use tracing_json_span_fields::redact::FieldPattern;
+/// use tracing_json_span_fields::JsonLayer;
+/// # std::env::set_var("LOG_HASH_KEY", "a secret key of at least 32 bytes");
+/// let key = std::env::var("LOG_HASH_KEY").expect("LOG_HASH_KEY must be set");
+/// let layer = JsonLayer::default().with_pseudonymizer(
+///     Pseudonymizer::new("2023-07", key).field(FieldPattern::glob("*user_id")),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct Pseudonymizer {
+    key_id: String,
+    mac: Hmac<Sha256>,
+    fields: Vec<FieldPattern>,
+    hash_len: usize,
+}
+
+impl Pseudonymizer {
+    /// Creates a pseudonymizer hashing with `key`, identified in the hashes by `key_id`.
+    ///
+    /// The key should be random and at least 32 bytes long, as anyone knowing it can
+    /// recompute the hashes of guessed values.
+    ///
+    /// # Panics
+    ///
+    /// If `key` is empty.
+    pub fn new(key_id: impl Into<String>, key: impl AsRef<[u8]>) -> Self {
+        let key = key.as_ref();
+        assert!(
+            !key.is_empty(),
+            "the pseudonymization key must not be empty"
+        );
+        Pseudonymizer {
+            key_id: key_id.into(),
+            mac: Hmac::new_from_slice(key).expect("HMAC accepts keys of any length"),
+            fields: vec![],
+            hash_len: 16,
+        }
+    }
+
+    /// Pseudonymizes fields with names matching `pattern`.
+    pub fn field(mut self, pattern: FieldPattern) -> Pseudonymizer {
+        self.fields.push(pattern);
+        self
+    }
+
+    /// The number of bytes of the digest to keep, at most 32, by default 16.
+    pub fn hash_len(self, hash_len: usize) -> Pseudonymizer {
+        Pseudonymizer {
+            hash_len: hash_len.min(32),
+            ..self
+        }
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        self.fields.iter().any(|pattern| pattern.matches(name))
+    }
+
+    pub(crate) fn pseudonymize(&self, value: &Value) -> Value {
+        let mut mac = self.mac.clone();
+        match value {
+            Value::String(s) => mac.update(s.as_bytes()),
+            value => mac.update(value.to_string().as_bytes()),
+        }
+        let digest = mac.finalize().into_bytes();
+
+        let mut pseudonym = format!("{}:", self.key_id);
+        for byte in &digest[..self.hash_len] {
+            let _ = write!(pseudonym, "{:02x}", byte);
+        }
+        pseudonym.into()
+    }
+}
+
+impl std::fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Leave out the key
+        f.debug_struct("Pseudonymizer")
+            .field("key_id", &self.key_id)
+            .field("fields", &self.fields)
+            .field("hash_len", &self.hash_len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn keyed_hashes() {
+        // Test case 2 of RFC 4231
+        let pseudonymizer = Pseudonymizer::new("k1", "Jefe").hash_len(32);
+        assert_eq!(
+            json!("k1:5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            pseudonymizer.pseudonymize(&json!("what do ya want for nothing?"))
+        );
+
+        let short = Pseudonymizer::new("k1", "Jefe").hash_len(4);
+        assert_eq!(
+            json!("k1:5bdcc146"),
+            short.pseudonymize(&json!("what do ya want for nothing?"))
+        );
+        assert_eq!(
+            short.pseudonymize(&json!(42)),
+            short.pseudonymize(&json!("42"))
+        );
+        assert_ne!(
+            short.pseudonymize(&json!("user")),
+            Pseudonymizer::new("k2", "other")
+                .hash_len(4)
+                .pseudonymize(&json!("user"))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "the pseudonymization key must not be empty")]
+    fn empty_key_is_rejected() {
+        Pseudonymizer::new("k1", "");
+    }
+}
@@ -0,0 +1,144 @@
+use indexmap::IndexMap;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// The fields of a record being written, borrowing the fields of spans and events where
+/// possible instead of cloning them.
+///
+/// The fields are serialized in the order they were inserted with the `preserve_order`
+/// feature, and alphabetically otherwise, like a [`Map`].
+#[derive(Debug, Default)]
+pub(crate) struct JsonRecord<'a> {
+    fields: IndexMap<Cow<'a, str>, Cow<'a, Value>, FnvBuildHasher>,
+}
+
+/// Builds [`FnvHasher`]s, for maps keyed by field names.
+pub(crate) type FnvBuildHasher = BuildHasherDefault<FnvHasher>;
+
+/// The FNV-1a hash, much faster than the default hasher for short keys like field names, which
+/// come from the program rather than from untrusted input.
+pub(crate) struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl<'a> JsonRecord<'a> {
+    pub(crate) fn new() -> Self {
+        JsonRecord::default()
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.fields.contains_key(key)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &str) -> Option<&mut Cow<'a, Value>> {
+        self.fields.get_mut(key)
+    }
+
+    /// Adds a field, replacing the value of an existing field with the same name.
+    pub(crate) fn insert(&mut self, key: impl Into<Cow<'a, str>>, value: Cow<'a, Value>) {
+        self.fields.insert(key.into(), value);
+    }
+
+    /// Removes a field, keeping the order of the remaining fields.
+    pub(crate) fn remove(&mut self, key: &str) -> Option<Cow<'a, Value>> {
+        self.fields.shift_remove(key)
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &str> {
+        self.fields.keys().map(AsRef::as_ref)
+    }
+
+    pub(crate) fn into_map(self) -> Map<String, Value> {
+        self.fields
+            .into_iter()
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect()
+    }
+}
+
+impl<'a> IntoIterator for JsonRecord<'a> {
+    type Item = (Cow<'a, str>, Cow<'a, Value>);
+    type IntoIter = indexmap::map::IntoIter<Cow<'a, str>, Cow<'a, Value>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.fields.into_iter()
+    }
+}
+
+impl Serialize for JsonRecord<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut fields: Vec<_> = self.fields.iter().collect();
+        if !cfg!(feature = "preserve_order") {
+            fields.sort_unstable_by_key(|(key, _)| *key);
+        }
+
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+        for (key, value) in fields {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn serialized_like_a_map() {
+        let shared = json!({"nested": [1, 2]});
+        let mut record = JsonRecord::new();
+        record.insert("b", Cow::Owned(json!(1)));
+        record.insert("a", Cow::Borrowed(&shared));
+        record.insert("c", Cow::Owned(json!(3)));
+        record.insert("b", Cow::Owned(json!(2)));
+        assert_eq!(Some(Cow::Owned(json!(3))), record.remove("c"));
+
+        let expected = if cfg!(feature = "preserve_order") {
+            r#"{"b":2,"a":{"nested":[1,2]}}"#
+        } else {
+            r#"{"a":{"nested":[1,2]},"b":2}"#
+        };
+        assert_eq!(expected, serde_json::to_string(&record).unwrap());
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            serde_json::to_string(&record.into_map()).unwrap()
+        );
+    }
+
+    #[test]
+    fn fnv_1a_test_vectors() {
+        // From the FNV reference test suite, for the 64 bit FNV-1a hash
+        for (input, expected) in [
+            ("", 0xcbf2_9ce4_8422_2325),
+            ("a", 0xaf63_dc4c_8601_ec8c),
+            ("foobar", 0x8594_4171_f739_67e8),
+        ] {
+            let mut hasher = FnvHasher::default();
+            hasher.write(input.as_bytes());
+            assert_eq!(expected, hasher.finish(), "hash of {:?}", input);
+        }
+    }
+}
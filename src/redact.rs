@@ -0,0 +1,248 @@
+//! Keeping secrets out of the fields written by a [`JsonLayer`](crate::JsonLayer).
+
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+
+/// The value written instead of a redacted field or substring.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// A pattern matching the names of fields.
+#[derive(Clone, Debug)]
+pub struct FieldPattern(Matcher);
+
+#[derive(Clone, Debug)]
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl FieldPattern {
+    /// Matches fields with exactly this name.
+    pub fn exact(name: impl Into<String>) -> Self {
+        FieldPattern(Matcher::Exact(name.into()))
+    }
+
+    /// Matches field names against a glob, where `*` matches any number of characters and `?`
+    /// matches a single character, e.g. `*password*`.
+    pub fn glob(glob: &str) -> Self {
+        let mut pattern = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                c => pattern.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        pattern.push('$');
+        FieldPattern(Matcher::Regex(
+            Regex::new(&pattern).expect("escaped glob is a valid regex"),
+        ))
+    }
+
+    /// Matches field names containing a match of the regular expression. Use `^` and `$` to
+    /// match the whole name.
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(FieldPattern(Matcher::Regex(Regex::new(pattern)?)))
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match &self.0 {
+            Matcher::Exact(exact) => exact == name,
+            Matcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// A pattern matching secrets inside string values, which are replaced by [`REDACTED`].
+#[derive(Clone, Debug)]
+pub struct ValuePattern {
+    regex: Regex,
+    luhn: bool,
+}
+
+impl ValuePattern {
+    /// Masks all matches of the regular expression.
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(ValuePattern {
+            regex: Regex::new(pattern)?,
+            luhn: false,
+        })
+    }
+
+    /// Masks bearer tokens, such as in `Authorization: Bearer abc.def`.
+    pub fn bearer_tokens() -> Self {
+        ValuePattern {
+            regex: Regex::new(r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]+=*").expect("valid regex"),
+            luhn: false,
+        }
+    }
+
+    /// Masks sequences of 13 to 19 digits, optionally separated by spaces or dashes, with a
+    /// valid Luhn check digit, such as payment card numbers.
+    pub fn card_numbers() -> Self {
+        ValuePattern {
+            regex: Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").expect("valid regex"),
+            luhn: true,
+        }
+    }
+
+    fn mask<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        self.regex.replace_all(value, |captures: &regex::Captures| {
+            let found = &captures[0];
+            if self.luhn && !luhn_valid(found) {
+                found.to_string()
+            } else {
+                REDACTED.to_string()
+            }
+        })
+    }
+}
+
+/// Whether the digits in `number` have a valid Luhn check digit.
+// `u32::is_multiple_of` would need Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn luhn_valid(number: &str) -> bool {
+    let sum: u32 = number
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(i, digit)| match (i % 2, digit * 2) {
+            (0, _) => digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+/// Rules for removing secrets from the fields of spans and events, including the message.
+///
+/// Fields with a name matching one of the [`FieldPattern`]s are replaced by [`REDACTED`], as
+/// are values nested in objects with a key matching one of them. Substrings of string values
+/// matching one of the [`ValuePattern`]s are replaced by [`REDACTED`], also within nested
+/// objects and arrays. Values recorded using `Debug` are strings, and are scanned as well.
+///
+/// ```
+/// use tracing_json_span_fields::redact::{FieldPattern, Redaction, ValuePattern};
+/// use tracing_json_span_fields::JsonLayer;
+/// let layer = JsonLayer::default().with_redaction(
+///     Redaction::new()
+///         .field(FieldPattern::exact("api_key"))
+///         .field(FieldPattern::glob("*password*"))
+///         .value(ValuePattern::bearer_tokens())
+///         .value(ValuePattern::card_numbers()),
+/// );
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Redaction {
+    fields: Vec<FieldPattern>,
+    values: Vec<ValuePattern>,
+}
+
+impl Redaction {
+    pub fn new() -> Self {
+        Redaction::default()
+    }
+
+    /// Redacts fields with names matching `pattern`.
+    pub fn field(mut self, pattern: FieldPattern) -> Redaction {
+        self.fields.push(pattern);
+        self
+    }
+
+    /// Masks substrings of values matching `pattern`.
+    pub fn value(mut self, pattern: ValuePattern) -> Redaction {
+        self.values.push(pattern);
+        self
+    }
+
+    /// Whether the whole value of the field `name` is redacted.
+    pub(crate) fn redacts_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|pattern| pattern.matches(name))
+    }
+
+    /// Masks the substrings of `value`, and of the strings nested in it, matching the value
+    /// patterns.
+    pub(crate) fn mask(&self, value: Value) -> Value {
+        if self.values.is_empty() {
+            return value;
+        }
+        match value {
+            Value::String(s) => {
+                let mut masked = Cow::Borrowed(s.as_str());
+                for pattern in &self.values {
+                    if let Cow::Owned(replaced) = pattern.mask(&masked) {
+                        masked = Cow::Owned(replaced);
+                    }
+                }
+                match masked {
+                    Cow::Borrowed(_) => Value::String(s),
+                    Cow::Owned(masked) => Value::String(masked),
+                }
+            }
+            Value::Array(values) => values.into_iter().map(|value| self.mask(value)).collect(),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, self.mask(value)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn field_patterns() {
+        let exact = FieldPattern::exact("token");
+        assert!(exact.matches("token"));
+        assert!(!exact.matches("tokens"));
+
+        let glob = FieldPattern::glob("*pass?ord*");
+        assert!(glob.matches("db_password"));
+        assert!(glob.matches("passWord"));
+        assert!(!glob.matches("pass_word"));
+
+        let dot = FieldPattern::glob("http.*");
+        assert!(dot.matches("http.authorization"));
+        assert!(!dot.matches("https"));
+
+        let regex = FieldPattern::regex("(?i)^secret").unwrap();
+        assert!(regex.matches("Secret_key"));
+        assert!(!regex.matches("my_secret"));
+    }
+
+    #[test]
+    fn value_patterns() {
+        let redaction = Redaction::new()
+            .value(ValuePattern::bearer_tokens())
+            .value(ValuePattern::card_numbers());
+        assert_eq!(
+            json!("Authorization: [REDACTED]"),
+            redaction.mask(json!("Authorization: Bearer abc.DEF-123="))
+        );
+        assert_eq!(
+            json!("paid with [REDACTED] and [REDACTED]"),
+            redaction.mask(json!(
+                "paid with 4111 1111 1111 1111 and 5500-0000-0000-0004"
+            ))
+        );
+        assert_eq!(
+            json!("order 1234567890123"),
+            redaction.mask(json!("order 1234567890123")),
+            "not a valid card number"
+        );
+        assert_eq!(json!(42), redaction.mask(json!(42)));
+        assert_eq!(
+            json!({"headers": ["[REDACTED]"], "id": 1}),
+            redaction.mask(json!({"headers": ["Bearer abc"], "id": 1}))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Global fields describing where the program is running, read from environment variables.
+
+use serde_json::{Map, Value};
+
+/// The Kubernetes downward API variables read by [`ResourceFields`], and the field names they
+/// are written as.
+const KUBERNETES_VARIABLES: [(&str, &str); 3] = [
+    ("POD_NAME", "pod_name"),
+    ("POD_NAMESPACE", "pod_namespace"),
+    ("NODE_NAME", "node_name"),
+];
+
+/// Builds a `resource` object from environment variables, to be added to every record using
+/// [`JsonLayer::with_static_fields`](crate::JsonLayer::with_static_fields).
+///
+/// Variables named with the prefix, by default `LOG_FIELD_`, are added with the prefix removed,
+/// so `LOG_FIELD_region=eu` becomes `"region": "eu"`. The Kubernetes downward API variables
+/// `POD_NAME`, `POD_NAMESPACE` and `NODE_NAME` are added as `pod_name`, `pod_namespace` and
+/// `node_name`.
+///
+/// ```
+/// use tracing_json_span_fields::resource::ResourceFields;
+/// use tracing_json_span_fields::JsonLayer;
+/// let layer = JsonLayer::default().with_static_fields(ResourceFields::new().build());
+/// ```
+///
+/// With `LOG_FIELD_region=eu` and `POD_NAME=my-service-1234` this adds the following to every
+/// record
+///
+/// ```json
+/// {"resource":{"pod_name":"my-service-1234","region":"eu"}}
+/// ```
+#[derive(Clone, Debug)]
+pub struct ResourceFields {
+    prefix: Option<String>,
+    kubernetes: bool,
+    object_name: String,
+}
+
+impl Default for ResourceFields {
+    fn default() -> Self {
+        ResourceFields {
+            prefix: Some("LOG_FIELD_".to_string()),
+            kubernetes: true,
+            object_name: "resource".to_string(),
+        }
+    }
+}
+
+impl ResourceFields {
+    pub fn new() -> Self {
+        ResourceFields::default()
+    }
+
+    /// The prefix of variables to add, or `None` to not add any prefixed variables.
+    pub fn prefix(self, prefix: Option<impl Into<String>>) -> ResourceFields {
+        ResourceFields {
+            prefix: prefix.map(Into::into),
+            ..self
+        }
+    }
+
+    /// Whether to add the Kubernetes downward API variables.
+    pub fn kubernetes(self, kubernetes: bool) -> ResourceFields {
+        ResourceFields { kubernetes, ..self }
+    }
+
+    /// The name of the object holding the fields, by default `resource`.
+    pub fn object_name(self, object_name: impl Into<String>) -> ResourceFields {
+        ResourceFields {
+            object_name: object_name.into(),
+            ..self
+        }
+    }
+
+    /// Reads the environment variables. The result is empty if none of the variables are set.
+    pub fn build(&self) -> Map<String, Value> {
+        self.build_from(std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
+
+    fn build_from(&self, vars: impl IntoIterator<Item = (String, String)>) -> Map<String, Value> {
+        let mut resource = Map::new();
+        for (name, value) in vars {
+            let prefixed = self
+                .prefix
+                .as_deref()
+                .and_then(|prefix| name.strip_prefix(prefix))
+                .filter(|field| !field.is_empty());
+            if let Some(field) = prefixed {
+                resource.insert(field.to_string(), value.into());
+            } else if self.kubernetes {
+                if let Some((_, field)) = KUBERNETES_VARIABLES.iter().find(|(var, _)| *var == name)
+                {
+                    resource.insert(field.to_string(), value.into());
+                }
+            }
+        }
+
+        let mut fields = Map::new();
+        if !resource.is_empty() {
+            fields.insert(self.object_name.clone(), resource.into());
+        }
+        fields
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn vars() -> Vec<(String, String)> {
+        [
+            ("LOG_FIELD_region", "eu"),
+            ("LOG_FIELD_", "no name"),
+            ("POD_NAME", "my-service-1234"),
+            ("NODE_NAME", "node-1"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .to_vec()
+    }
+
+    #[test]
+    fn prefixed_and_kubernetes() {
+        assert_eq!(
+            json!({
+                "resource": {
+                    "region": "eu",
+                    "pod_name": "my-service-1234",
+                    "node_name": "node-1",
+                }
+            }),
+            Value::Object(ResourceFields::new().build_from(vars()))
+        );
+    }
+
+    #[test]
+    fn custom_prefix_without_kubernetes() {
+        let fields = ResourceFields::new()
+            .prefix(Some("POD_"))
+            .kubernetes(false)
+            .object_name("env")
+            .build_from(vars());
+        assert_eq!(
+            json!({"env": {"NAME": "my-service-1234"}}),
+            Value::Object(fields)
+        );
+    }
+
+    #[test]
+    fn nothing_set() {
+        let fields = ResourceFields::new()
+            .prefix(None::<String>)
+            .build_from(vec![]);
+        assert!(fields.is_empty());
+    }
+}
@@ -0,0 +1,516 @@
+//! A [`JsonOutput`] writing to files that are rotated by size and/or time.
+
+use crate::{json_line, Error, JsonOutput};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+use time::format_description::BorrowedFormatItem;
+use time::formatting::Formattable;
+use time::{Duration, OffsetDateTime, Time};
+
+/// How often a [`RotatingFileOutput`] starts a new file regardless of its size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    /// Only rotate by size.
+    #[default]
+    Never,
+    /// Rotate at the start of every hour (UTC).
+    Hourly,
+    /// Rotate at midnight (UTC).
+    Daily,
+}
+
+impl Rotation {
+    /// The first rotation boundary after `time`.
+    fn next_after(&self, time: OffsetDateTime) -> Option<OffsetDateTime> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => {
+                let hour = Time::from_hms(time.hour(), 0, 0).expect("valid hour");
+                Some(time.replace_time(hour) + Duration::HOUR)
+            }
+            Rotation::Daily => Some(time.replace_time(Time::MIDNIGHT) + Duration::DAY),
+        }
+    }
+}
+
+/// A [`JsonOutput`] writing newline delimited JSON to a file in a directory, rotating it by size
+/// and/or by time.
+///
+/// Events are written to `<prefix>.log`. When the file is rotated it is renamed to
+/// `<prefix>.<timestamp>.log`, where the timestamp is the time the file was started, formatted
+/// using a [`time` format description](time::format_description). With the `gzip` feature the
+/// rotated files can also be compressed.
+///
+/// Rotation, compression and removal of old files happen on the thread writing the event that
+/// triggers them. Wrap the output in a
+/// [`NonBlockingOutput`](crate::non_blocking::NonBlockingOutput) to keep this off the threads
+/// doing the logging.
+///
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+/// use tracing_json_span_fields::rotating::{RotatingFileOutput, Rotation};
+/// use tracing_json_span_fields::JsonLayer;
+/// let output = RotatingFileOutput::builder("/var/log/my-service", "my-service")
+///     .rotation(Rotation::Daily)
+///     .max_size(100 * 1024 * 1024)
+///     .max_files(7)
+///     .build()
+///     .expect("log directory should be writable");
+/// tracing_subscriber::registry().with(JsonLayer::default().with_output(output)).init();
+/// ```
+pub struct RotatingFileOutput<F = Vec<BorrowedFormatItem<'static>>> {
+    config: RotatingFileBuilder<F>,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    size: u64,
+    started: OffsetDateTime,
+    next_rotation: Option<OffsetDateTime>,
+}
+
+impl RotatingFileOutput {
+    /// Starts configuring output to `<directory>/<prefix>.log`.
+    pub fn builder(directory: impl AsRef<Path>, prefix: impl Into<String>) -> RotatingFileBuilder {
+        RotatingFileBuilder {
+            directory: directory.as_ref().to_path_buf(),
+            prefix: prefix.into(),
+            rotation: Rotation::default(),
+            max_size: None,
+            max_files: None,
+            #[cfg(feature = "gzip")]
+            compress: false,
+            file_name_format: time::format_description::parse_borrowed::<1>(
+                "[year]-[month]-[day]T[hour]-[minute]-[second]",
+            )
+            .expect("the default file name format should be valid"),
+        }
+    }
+}
+
+/// Configuration for a [`RotatingFileOutput`].
+pub struct RotatingFileBuilder<F = Vec<BorrowedFormatItem<'static>>> {
+    directory: PathBuf,
+    prefix: String,
+    rotation: Rotation,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    #[cfg(feature = "gzip")]
+    compress: bool,
+    file_name_format: F,
+}
+
+impl<F> RotatingFileBuilder<F>
+where
+    F: Formattable,
+{
+    pub fn rotation(self, rotation: Rotation) -> RotatingFileBuilder<F> {
+        RotatingFileBuilder { rotation, ..self }
+    }
+
+    /// Rotates the file before it would grow beyond `max_size` bytes.
+    pub fn max_size(self, max_size: u64) -> RotatingFileBuilder<F> {
+        RotatingFileBuilder {
+            max_size: Some(max_size),
+            ..self
+        }
+    }
+
+    /// The number of rotated files to keep. The oldest files are removed first.
+    pub fn max_files(self, max_files: usize) -> RotatingFileBuilder<F> {
+        RotatingFileBuilder {
+            max_files: Some(max_files),
+            ..self
+        }
+    }
+
+    /// Compresses rotated files with gzip, adding `.gz` to their names.
+    #[cfg(feature = "gzip")]
+    pub fn compress(self, compress: bool) -> RotatingFileBuilder<F> {
+        RotatingFileBuilder { compress, ..self }
+    }
+
+    /// The format of the timestamp in the names of rotated files.
+    ///
+    /// The timestamps should sort in chronological order and must not contain characters that
+    /// are invalid in file names.
+    pub fn file_name_format<F2>(self, file_name_format: F2) -> RotatingFileBuilder<F2>
+    where
+        F2: Formattable,
+    {
+        RotatingFileBuilder {
+            directory: self.directory,
+            prefix: self.prefix,
+            rotation: self.rotation,
+            max_size: self.max_size,
+            max_files: self.max_files,
+            #[cfg(feature = "gzip")]
+            compress: self.compress,
+            file_name_format,
+        }
+    }
+
+    /// Creates the directory if needed and opens the file for appending.
+    pub fn build(self) -> std::io::Result<RotatingFileOutput<F>> {
+        std::fs::create_dir_all(&self.directory)?;
+        let file = self.open_active()?;
+        let metadata = file.metadata()?;
+        // Continue an existing file as if it was started when it was last written
+        let started = if metadata.len() > 0 {
+            metadata
+                .modified()
+                .map(OffsetDateTime::from)
+                .unwrap_or_else(|_| OffsetDateTime::now_utc())
+        } else {
+            OffsetDateTime::now_utc()
+        };
+        let state = State {
+            file,
+            size: metadata.len(),
+            started,
+            next_rotation: self.rotation.next_after(started),
+        };
+        Ok(RotatingFileOutput {
+            config: self,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn active_path(&self) -> PathBuf {
+        self.directory.join(format!("{}.log", self.prefix))
+    }
+
+    fn open_active(&self) -> std::io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.active_path())
+    }
+}
+
+impl<F> RotatingFileOutput<F>
+where
+    F: Formattable,
+{
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write_at(&self, value: &Value, now: OffsetDateTime) -> Result<(), Error> {
+        self.write_line_at(&json_line(value, false)?, now)
+    }
+
+    fn write_line_at(&self, line: &[u8], now: OffsetDateTime) -> Result<(), Error> {
+        let mut state = self.lock();
+
+        let due_by_time = matches!(state.next_rotation, Some(next) if now >= next);
+        let new_size = state.size + line.len() as u64;
+        let due_by_size =
+            matches!(self.config.max_size, Some(max) if state.size > 0 && new_size > max);
+        // Keep writing to the current file if rotating fails, but still report the failure
+        let rotation_result = if due_by_time || due_by_size {
+            self.rotate(&mut state, now)
+        } else {
+            Ok(())
+        };
+
+        state.file.write_all(line)?;
+        state.size += line.len() as u64;
+        rotation_result
+    }
+
+    fn rotate(&self, state: &mut State, now: OffsetDateTime) -> Result<(), Error> {
+        if state.size == 0 {
+            // Nothing to keep, just start over
+            state.started = now;
+            state.next_rotation = self.config.rotation.next_after(now);
+            return Ok(());
+        }
+
+        let rotated = self.rotated_path(state.started)?;
+        std::fs::rename(self.config.active_path(), &rotated)?;
+        state.file = self.config.open_active()?;
+        state.size = 0;
+        state.started = now;
+        state.next_rotation = self.config.rotation.next_after(now);
+
+        #[cfg(feature = "gzip")]
+        if self.config.compress {
+            compress(&rotated)?;
+        }
+        self.remove_old_files(now)?;
+        Ok(())
+    }
+
+    /// A path for a rotated file not clashing with any existing file.
+    fn rotated_path(&self, started: OffsetDateTime) -> Result<PathBuf, Error> {
+        let timestamp = started.format(&self.config.file_name_format)?;
+        let base = format!("{}.{}", self.config.prefix, timestamp);
+        let mut name = format!("{}.log", base);
+        let mut counter = 0;
+        while self.config.directory.join(&name).exists()
+            || self.config.directory.join(format!("{}.gz", name)).exists()
+        {
+            counter += 1;
+            name = format!("{}.{}.log", base, counter);
+        }
+        Ok(self.config.directory.join(name))
+    }
+
+    /// Removes the oldest rotated files beyond `max_files`. Only files named like the rotated
+    /// files, with a timestamp shaped like `now` formatted, are considered.
+    fn remove_old_files(&self, now: OffsetDateTime) -> Result<(), Error> {
+        let max_files = match self.config.max_files {
+            Some(max_files) => max_files,
+            None => return Ok(()),
+        };
+
+        let sample = now.format(&self.config.file_name_format)?;
+        let active = self.config.active_path();
+        let mut rotated = vec![];
+        for entry in std::fs::read_dir(&self.config.directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            let is_rotated = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| self.is_rotated_name(name, &sample))
+                && path != active
+                && entry.file_type()?.is_file();
+            if is_rotated {
+                let modified = entry
+                    .metadata()?
+                    .modified()
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                rotated.push((modified, path));
+            }
+        }
+
+        if rotated.len() > max_files {
+            rotated.sort();
+            for (_, path) in &rotated[..rotated.len() - max_files] {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is `<prefix>.<timestamp>[.<counter>].log[.gz]`, with a timestamp shaped
+    /// like `sample`.
+    fn is_rotated_name(&self, name: &str, sample: &str) -> bool {
+        let rest = match name
+            .strip_prefix(self.config.prefix.as_str())
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| {
+                rest.strip_suffix(".log.gz")
+                    .or_else(|| rest.strip_suffix(".log"))
+            }) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        same_shape(rest, sample)
+            || rest.rsplit_once('.').is_some_and(|(timestamp, counter)| {
+                !counter.is_empty()
+                    && counter.bytes().all(|b| b.is_ascii_digit())
+                    && same_shape(timestamp, sample)
+            })
+    }
+}
+
+/// Whether `a` and `b` are the same, except for the digits and letters in them.
+fn same_shape(a: &str, b: &str) -> bool {
+    a.chars().count() == b.chars().count()
+        && a.chars().zip(b.chars()).all(|(a, b)| {
+            a == b
+                || (a.is_ascii_digit() && b.is_ascii_digit())
+                || (a.is_alphabetic() && b.is_alphabetic())
+        })
+}
+
+impl<F> JsonOutput for RotatingFileOutput<F>
+where
+    F: Formattable,
+{
+    fn write(&self, value: Value) -> Result<(), Error> {
+        self.write_at(&value, OffsetDateTime::now_utc())
+    }
+
+    fn write_bytes(&self, line: &[u8]) -> Result<(), Error> {
+        self.write_line_at(line, OffsetDateTime::now_utc())
+    }
+
+    fn accepts_bytes(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        Ok(self.lock().file.flush()?)
+    }
+}
+
+/// Replaces the file at `path` with a gzip compressed copy named `<path>.gz`.
+#[cfg(feature = "gzip")]
+fn compress(path: &Path) -> std::io::Result<()> {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".gz");
+
+    let mut input = File::open(path)?;
+    let mut encoder = flate2::write::GzEncoder::new(
+        File::create(&compressed_path)?,
+        flate2::Compression::default(),
+    );
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use time::macros::{datetime, format_description};
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn read(path: PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let directory = tempfile::tempdir().unwrap();
+        let output = RotatingFileOutput::builder(directory.path(), "test")
+            .max_size(20)
+            .file_name_format(format_description!("[year][month][day]"))
+            .build()
+            .unwrap();
+
+        let now = datetime!(2023-07-25 10:00 UTC);
+        output.lock().started = now;
+        output.write_at(&json!({"n": 1}), now).unwrap();
+        output.write_at(&json!({"n": 2}), now).unwrap();
+        output.write_at(&json!({"n": 3}), now).unwrap();
+
+        assert_eq!(
+            vec!["test.20230725.log", "test.log"],
+            file_names(directory.path())
+        );
+        assert_eq!(
+            "{\"n\":1}\n{\"n\":2}\n",
+            read(directory.path().join("test.20230725.log"))
+        );
+        assert_eq!("{\"n\":3}\n", read(directory.path().join("test.log")));
+    }
+
+    #[test]
+    fn writes_serialized_lines() {
+        let directory = tempfile::tempdir().unwrap();
+        let output = RotatingFileOutput::builder(directory.path(), "test")
+            .build()
+            .unwrap();
+
+        assert!(output.accepts_bytes());
+        output.write_bytes(b"{\"n\":1}\n").unwrap();
+        output.write(json!({"n": 2})).unwrap();
+        output.flush().unwrap();
+
+        assert_eq!(vec!["test.log"], file_names(directory.path()));
+        assert_eq!(
+            "{\"n\":1}\n{\"n\":2}\n",
+            read(directory.path().join("test.log"))
+        );
+    }
+
+    #[test]
+    fn rotates_by_time_and_removes_old_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let output = RotatingFileOutput::builder(directory.path(), "test")
+            .rotation(Rotation::Hourly)
+            .max_files(2)
+            .file_name_format(format_description!("[hour]"))
+            .build()
+            .unwrap();
+        for other in [
+            "other.09.log",
+            "test.worker.log",
+            "test.09.log.bak",
+            "test.9.log",
+        ] {
+            std::fs::write(directory.path().join(other), "").unwrap();
+        }
+
+        output.lock().next_rotation = Some(datetime!(2023-07-25 10:00 UTC));
+        for hour in 10..14 {
+            let now = datetime!(2023-07-25 00:00 UTC) + Duration::hours(hour);
+            output.write_at(&json!({ "hour": hour }), now).unwrap();
+            output
+                .write_at(&json!({ "hour": hour }), now + Duration::minutes(59))
+                .unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                "other.09.log",
+                "test.09.log.bak",
+                "test.11.log",
+                "test.12.log",
+                "test.9.log",
+                "test.log",
+                "test.worker.log",
+            ],
+            file_names(directory.path()),
+            "only rotated files are removed"
+        );
+        assert_eq!(
+            "{\"hour\":12}\n{\"hour\":12}\n",
+            read(directory.path().join("test.12.log"))
+        );
+        assert_eq!(
+            "{\"hour\":13}\n{\"hour\":13}\n",
+            read(directory.path().join("test.log"))
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compresses_rotated_files() {
+        use std::io::Read;
+
+        let directory = tempfile::tempdir().unwrap();
+        let output = RotatingFileOutput::builder(directory.path(), "test")
+            .max_size(1)
+            .compress(true)
+            .file_name_format(format_description!("[year]"))
+            .build()
+            .unwrap();
+
+        let now = datetime!(2023-07-25 10:00 UTC);
+        output.lock().started = now;
+        output.write_at(&json!({"n": 1}), now).unwrap();
+        output.write_at(&json!({"n": 2}), now).unwrap();
+
+        assert_eq!(
+            vec!["test.2023.log.gz", "test.log"],
+            file_names(directory.path())
+        );
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(
+            File::open(directory.path().join("test.2023.log.gz")).unwrap(),
+        )
+        .read_to_string(&mut decompressed)
+        .unwrap();
+        assert_eq!("{\"n\":1}\n", decompressed);
+    }
+}
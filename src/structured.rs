@@ -0,0 +1,257 @@
+//! Converting [`valuable`] values recorded for fields into nested JSON.
+
+use serde_json::{Map, Value};
+use valuable::{Fields, NamedValues, Visit};
+
+/// Converts `value` into JSON the way serde would serialize the Rust value: structs as objects,
+/// lists and tuples as arrays, and enums as the name of the variant, or an object with the
+/// variant as the only key.
+pub(crate) fn to_json(value: valuable::Value<'_>) -> Value {
+    use valuable::Value as V;
+    match value {
+        V::Bool(b) => b.into(),
+        V::Char(c) => c.to_string().into(),
+        V::F32(f) => f.into(),
+        V::F64(f) => f.into(),
+        V::I8(i) => i.into(),
+        V::I16(i) => i.into(),
+        V::I32(i) => i.into(),
+        V::I64(i) => i.into(),
+        V::I128(i) => i64::try_from(i).map_or_else(|_| i.to_string().into(), Value::from),
+        V::Isize(i) => i.into(),
+        V::String(s) => s.into(),
+        V::U8(u) => u.into(),
+        V::U16(u) => u.into(),
+        V::U32(u) => u.into(),
+        V::U64(u) => u.into(),
+        V::U128(u) => u64::try_from(u).map_or_else(|_| u.to_string().into(), Value::from),
+        V::Usize(u) => u.into(),
+        V::Path(path) => path.to_string_lossy().into(),
+        V::Error(e) => e.to_string().into(),
+        V::Listable(list) => {
+            let mut collector = Collector::array();
+            list.visit(&mut collector);
+            collector.0
+        }
+        V::Mappable(map) => {
+            let mut collector = Collector::object();
+            map.visit(&mut collector);
+            collector.0
+        }
+        V::Structable(structable) => fields(structable.definition().fields(), |collector| {
+            structable.visit(collector)
+        }),
+        V::Enumerable(enumerable) => {
+            let variant = enumerable.variant();
+            let name = variant.name().to_string();
+            match variant.fields() {
+                Fields::Unnamed(0) => name.into(),
+                variant_fields => {
+                    let value = fields(variant_fields, |collector| enumerable.visit(collector));
+                    Value::Object(Map::from_iter([(name, value)]))
+                }
+            }
+        }
+        V::Tuplable(tuple) => {
+            let mut collector = Collector::array();
+            tuple.visit(&mut collector);
+            match collector.0 {
+                Value::Array(values) if values.is_empty() => Value::Null,
+                values => values,
+            }
+        }
+        V::Unit => Value::Null,
+        // Variants added to valuable later
+        other => format!("{:?}", other).into(),
+    }
+}
+
+/// The fields of a struct or an enum variant: an object for named fields, the value for a
+/// single unnamed field, an array for several unnamed fields, and null for none.
+fn fields(fields: &Fields<'_>, visit: impl FnOnce(&mut Collector)) -> Value {
+    match fields {
+        Fields::Named(_) => {
+            let mut collector = Collector::object();
+            visit(&mut collector);
+            collector.0
+        }
+        Fields::Unnamed(_) => {
+            let mut collector = Collector::array();
+            visit(&mut collector);
+            match collector.0 {
+                Value::Array(mut values) if values.len() <= 1 => {
+                    values.pop().unwrap_or(Value::Null)
+                }
+                values => values,
+            }
+        }
+    }
+}
+
+/// Collects the values visited into an array or an object.
+struct Collector(Value);
+
+impl Collector {
+    fn array() -> Self {
+        Collector(Value::Array(vec![]))
+    }
+
+    fn object() -> Self {
+        Collector(Value::Object(Map::new()))
+    }
+}
+
+impl Visit for Collector {
+    fn visit_value(&mut self, value: valuable::Value<'_>) {
+        if let Value::Array(values) = &mut self.0 {
+            values.push(to_json(value));
+        }
+    }
+
+    fn visit_named_fields(&mut self, named_values: &NamedValues<'_>) {
+        if let Value::Object(fields) = &mut self.0 {
+            for (field, value) in named_values {
+                fields.insert(field.name().to_string(), to_json(*value));
+            }
+        }
+    }
+
+    fn visit_unnamed_fields(&mut self, values: &[valuable::Value<'_>]) {
+        if let Value::Array(array) = &mut self.0 {
+            array.extend(values.iter().map(|value| to_json(*value)));
+        }
+    }
+
+    fn visit_entry(&mut self, key: valuable::Value<'_>, value: valuable::Value<'_>) {
+        if let Value::Object(entries) = &mut self.0 {
+            // Keys that aren't strings are written as JSON, like serde_json does for numbers
+            let key = match to_json(key) {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+            entries.insert(key, to_json(value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use valuable::{
+        EnumDef, Enumerable, NamedField, StructDef, Structable, Valuable, Variant, VariantDef,
+    };
+
+    struct Request {
+        method: &'static str,
+        status: u16,
+        tags: Vec<&'static str>,
+    }
+
+    static REQUEST_FIELDS: &[NamedField<'static>] = &[
+        NamedField::new("method"),
+        NamedField::new("status"),
+        NamedField::new("tags"),
+    ];
+
+    impl Valuable for Request {
+        fn as_value(&self) -> valuable::Value<'_> {
+            valuable::Value::Structable(self)
+        }
+
+        fn visit(&self, visit: &mut dyn Visit) {
+            visit.visit_named_fields(&NamedValues::new(
+                REQUEST_FIELDS,
+                &[
+                    self.method.as_value(),
+                    self.status.as_value(),
+                    self.tags.as_value(),
+                ],
+            ));
+        }
+    }
+
+    impl Structable for Request {
+        fn definition(&self) -> StructDef<'_> {
+            StructDef::new_static("Request", Fields::Named(REQUEST_FIELDS))
+        }
+    }
+
+    enum Outcome {
+        Ok,
+        Retried(u8),
+        Failed(&'static str, u16),
+    }
+
+    static OUTCOME_VARIANTS: &[VariantDef<'static>] = &[
+        VariantDef::new("Ok", Fields::Unnamed(0)),
+        VariantDef::new("Retried", Fields::Unnamed(1)),
+        VariantDef::new("Failed", Fields::Unnamed(2)),
+    ];
+
+    impl Valuable for Outcome {
+        fn as_value(&self) -> valuable::Value<'_> {
+            valuable::Value::Enumerable(self)
+        }
+
+        fn visit(&self, visit: &mut dyn Visit) {
+            match self {
+                Outcome::Ok => visit.visit_unnamed_fields(&[]),
+                Outcome::Retried(n) => visit.visit_unnamed_fields(&[n.as_value()]),
+                Outcome::Failed(reason, code) => {
+                    visit.visit_unnamed_fields(&[reason.as_value(), code.as_value()])
+                }
+            }
+        }
+    }
+
+    impl Enumerable for Outcome {
+        fn definition(&self) -> EnumDef<'_> {
+            EnumDef::new_static("Outcome", OUTCOME_VARIANTS)
+        }
+
+        fn variant(&self) -> Variant<'_> {
+            let index = match self {
+                Outcome::Ok => 0,
+                Outcome::Retried(_) => 1,
+                Outcome::Failed(..) => 2,
+            };
+            Variant::Static(&OUTCOME_VARIANTS[index])
+        }
+    }
+
+    #[test]
+    fn nested_values() {
+        let request = Request {
+            method: "GET",
+            status: 200,
+            tags: vec!["a", "b"],
+        };
+        assert_eq!(
+            json!({"method": "GET", "status": 200, "tags": ["a", "b"]}),
+            to_json(request.as_value())
+        );
+
+        assert_eq!(json!("Ok"), to_json(Outcome::Ok.as_value()));
+        assert_eq!(
+            json!({"Retried": 2}),
+            to_json(Outcome::Retried(2).as_value())
+        );
+        assert_eq!(
+            json!({"Failed": ["timeout", 504]}),
+            to_json(Outcome::Failed("timeout", 504).as_value())
+        );
+
+        let map = BTreeMap::from([(1, (true, 'x')), (2, (false, 'y'))]);
+        assert_eq!(
+            json!({"1": [true, "x"], "2": [false, "y"]}),
+            to_json(map.as_value())
+        );
+        assert_eq!(json!(null), to_json(().as_value()));
+        assert_eq!(
+            json!("340282366920938463463374607431768211455"),
+            to_json(u128::MAX.as_value())
+        );
+    }
+}
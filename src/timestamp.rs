@@ -0,0 +1,178 @@
+//! Formats for the timestamps written by a [`JsonLayer`](crate::JsonLayer).
+
+use crate::Error;
+use serde_json::Value;
+use time::formatting::Formattable;
+use time::{OffsetDateTime, UtcOffset};
+
+/// How the timestamp of an event is written.
+///
+/// Any [`time` format description](time::format_description) writes the timestamp as a string,
+/// [`Epoch`] writes it as a number, and [`WithOffset`] and [`Local`] change the offset of the
+/// timestamp before formatting it.
+pub trait TimestampFormat {
+    fn format_timestamp(&self, timestamp: OffsetDateTime) -> Result<Value, Error>;
+}
+
+impl<F> TimestampFormat for F
+where
+    F: Formattable,
+{
+    fn format_timestamp(&self, timestamp: OffsetDateTime) -> Result<Value, Error> {
+        Ok(timestamp.format(self)?.into())
+    }
+}
+
+/// Writes timestamps as the number of seconds, or fractions of seconds, since the Unix epoch.
+///
+/// ```
+/// use tracing_json_span_fields::timestamp::Epoch;
+/// use tracing_json_span_fields::JsonLayer;
+/// let layer = JsonLayer::default().with_timestamp_format(Epoch::Millis);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Epoch {
+    Seconds,
+    Millis,
+    Micros,
+    Nanos,
+}
+
+impl TimestampFormat for Epoch {
+    fn format_timestamp(&self, timestamp: OffsetDateTime) -> Result<Value, Error> {
+        let nanos = timestamp.unix_timestamp_nanos();
+        let value = match self {
+            Epoch::Seconds => nanos.div_euclid(1_000_000_000),
+            Epoch::Millis => nanos.div_euclid(1_000_000),
+            Epoch::Micros => nanos.div_euclid(1_000),
+            Epoch::Nanos => nanos,
+        };
+        // Nanoseconds fit into an i64 until the year 2262
+        Ok(i64::try_from(value)
+            .unwrap_or(if value < 0 { i64::MIN } else { i64::MAX })
+            .into())
+    }
+}
+
+/// Converts timestamps to a fixed [`UtcOffset`] before formatting them.
+///
+/// ```
+/// use time::format_description::well_known::Rfc3339;
+/// use time::macros::offset;
+/// use tracing_json_span_fields::timestamp::WithOffset;
+/// use tracing_json_span_fields::JsonLayer;
+/// let layer = JsonLayer::default().with_timestamp_format(WithOffset::new(Rfc3339, offset!(+2)));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct WithOffset<F> {
+    format: F,
+    offset: UtcOffset,
+}
+
+impl<F> WithOffset<F>
+where
+    F: TimestampFormat,
+{
+    pub fn new(format: F, offset: UtcOffset) -> Self {
+        WithOffset { format, offset }
+    }
+}
+
+impl<F> TimestampFormat for WithOffset<F>
+where
+    F: TimestampFormat,
+{
+    fn format_timestamp(&self, timestamp: OffsetDateTime) -> Result<Value, Error> {
+        self.format
+            .format_timestamp(timestamp.to_offset(self.offset))
+    }
+}
+
+/// Converts timestamps to the local offset of the system before formatting them.
+///
+/// Determining the local offset is not always possible, e.g. on some Unix systems once the
+/// process has several threads. The offset is therefore determined when creating the format,
+/// and later used whenever the offset at the time of an event cannot be determined. Create the
+/// format early in `main` to make sure the fallback is correct.
+#[derive(Clone, Copy, Debug)]
+pub struct Local<F> {
+    format: F,
+    fallback: UtcOffset,
+}
+
+impl<F> Local<F>
+where
+    F: TimestampFormat,
+{
+    pub fn new(format: F) -> Self {
+        Local {
+            format,
+            fallback: UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC),
+        }
+    }
+}
+
+impl<F> TimestampFormat for Local<F>
+where
+    F: TimestampFormat,
+{
+    fn format_timestamp(&self, timestamp: OffsetDateTime) -> Result<Value, Error> {
+        let offset = UtcOffset::local_offset_at(timestamp).unwrap_or(self.fallback);
+        self.format.format_timestamp(timestamp.to_offset(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use time::format_description::well_known::Rfc3339;
+    use time::macros::{datetime, offset};
+
+    #[test]
+    fn epoch() {
+        let timestamp = datetime!(2023-07-25 09:53:01.790152227 UTC);
+        let formatted = [Epoch::Seconds, Epoch::Millis, Epoch::Micros, Epoch::Nanos]
+            .map(|epoch| epoch.format_timestamp(timestamp).unwrap());
+        assert_eq!(
+            [
+                json!(1690278781),
+                json!(1690278781790_i64),
+                json!(1690278781790152_i64),
+                json!(1690278781790152227_i64)
+            ],
+            formatted
+        );
+    }
+
+    #[test]
+    fn epoch_before_1970() {
+        let timestamp = datetime!(1969-12-31 23:59:59.5 UTC);
+        assert_eq!(
+            json!(-1),
+            Epoch::Seconds.format_timestamp(timestamp).unwrap()
+        );
+        assert_eq!(
+            json!(-500),
+            Epoch::Millis.format_timestamp(timestamp).unwrap()
+        );
+    }
+
+    #[test]
+    fn with_offset() {
+        let timestamp = datetime!(2023-07-25 09:53:01 UTC);
+        assert_eq!(
+            json!("2023-07-25T11:53:01+02:00"),
+            WithOffset::new(Rfc3339, offset!(+2))
+                .format_timestamp(timestamp)
+                .unwrap()
+        );
+        assert_eq!(
+            json!(1690278781),
+            WithOffset::new(Epoch::Seconds, offset!(+2))
+                .format_timestamp(timestamp)
+                .unwrap(),
+            "the offset does not change the instant"
+        );
+    }
+}